@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    io::Write,
+    path::Path,
+};
+
+use clap::ArgMatches;
+
+use crate::{
+    client_api::ClientApi,
+    commands::Commands,
+};
+
+/// The environment variable the admin token is read from.
+pub const ADMIN_TOKEN_VAR: &str = "RUST_CHAT_ADMIN_TOKEN";
+
+/// Run one of the subcommands that talk to a running server.
+/// Returns an error for the operator when the server refused or couldn't be reached.
+pub fn run(subcommand: &str, args: &ArgMatches) -> Result<(), String> {
+    let host = args.value_of("host").unwrap();
+
+    match subcommand {
+        "info" => match ClientApi::get_info(host) {
+            Ok(Commands::Info(Some(params))) => {
+                print_params(&params, &["name", "owner", "motd"]);
+                Ok(())
+            },
+            Ok(reply) => Err(describe(&reply)),
+            Err(e) => Err(format!("can't reach {}: {}", host, e)),
+        },
+        "clients" => {
+            let (reply, clients) = ClientApi::get_clients(host, &admin_token(args)?).map_err(|e| format!("can't reach {}: {}", host, e))?;
+            if !matches!(reply, Commands::Success(_)) {
+                return Err(describe(&reply));
+            }
+            for client in clients {
+                println!("{}\t{}\t{}",
+                         client.get("uuid").map(String::as_str).unwrap_or(""),
+                         client.get("name").map(String::as_str).unwrap_or(""),
+                         client.get("presence").map(String::as_str).unwrap_or(""));
+            }
+            Ok(())
+        },
+        "send" => {
+            let (to, room) = (args.value_of("to"), args.value_of("room"));
+            if to.is_none() && room.is_none() {
+                return Err(String::from("send needs either --to or --room"));
+            }
+            let secret_file = args.value_of("secret-file");
+            let secret = match secret_file {
+                Some(path) if Path::new(path).exists() => Some(fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?.trim().to_string()),
+                _ => None,
+            };
+            let (reply, issued) = ClientApi::send_message(host, args.value_of("uuid").unwrap(), args.value_of("as").unwrap(), secret.as_deref(), to, room, args.value_of("message").unwrap())
+                .map_err(|e| format!("can't reach {}: {}", host, e))?;
+            if let Some(issued) = issued {
+                match secret_file {
+                    Some(path) => write_secret(path, &issued).map_err(|e| format!("can't save the secret to {}: {}", path, e))?,
+                    None => println!("the server gave this uuid a secret, use --secret-file to keep it and send as it again"),
+                }
+            }
+            match reply {
+                Commands::Success(Some(params)) if params.contains_key("id") => {
+                    println!("sent message {}", params["id"]);
+                    Ok(())
+                },
+                reply => Err(describe(&reply)),
+            }
+        },
+        "admin" => {
+            let (action, action_args) = args.subcommand().ok_or("admin needs an action, see --help")?;
+            let mut params: HashMap<String, String> = [(String::from("action"), action.to_string())].iter().cloned().collect();
+            for key in ["uuid", "username", "address", "room", "reason", "duration", "content", "role"] {
+                if let Some(value) = action_args.value_of(key) {
+                    params.insert(key.to_string(), value.to_string());
+                }
+            }
+
+            match ClientApi::admin_list(host, &admin_token(args)?, params) {
+                Ok((Commands::Success(_), listed)) => {
+                    for command in listed {
+                        if let Commands::Ban(Some(ban)) = command {
+                            print_params(&ban, &["uuid", "username", "address", "reason", "created", "expires"]);
+                            println!();
+                        }
+                    }
+                    Ok(())
+                },
+                Ok((reply, _)) => Err(describe(&reply)),
+                Err(e) => Err(format!("can't reach {}: {}", host, e)),
+            }
+        },
+        _ => Err(format!("unknown command {}", subcommand)),
+    }
+}
+
+/// The admin token, from `--token-file` or the environment, so it never
+/// shows up in the process list.
+fn admin_token(args: &ArgMatches) -> Result<String, String> {
+    match args.value_of("token-file") {
+        Some(path) => fs::read_to_string(path).map(|token| token.trim().to_string()).map_err(|e| format!("can't read {}: {}", path, e)),
+        None => std::env::var(ADMIN_TOKEN_VAR).map_err(|_| format!("the admin token is needed, set {} or use --token-file", ADMIN_TOKEN_VAR)),
+    }
+}
+
+/// Save a secret where only its owner can read it.
+fn write_secret(path: &str, secret: &str) -> Result<(), io::Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", secret)
+}
+
+fn print_params(params: &HashMap<String, String>, keys: &[&str]) {
+    for key in keys {
+        if let Some(value) = params.get(*key) {
+            println!("{}: {}", key, value);
+        }
+    }
+}
+
+/// Explain a reply that wasn't the one we asked for.
+fn describe(reply: &Commands) -> String {
+    match reply {
+        Commands::Error(Some(params)) if params.contains_key("reason") => format!("the server refused: {}", params["reason"]),
+        Commands::Error(_) => String::from("the server refused"),
+        reply => format!("unexpected reply from the server: {}", reply.to_string()),
+    }
+}
@@ -1,7 +1,7 @@
-use std::{net::TcpStream, io::{Write, Read}, io};
+use std::{net::TcpStream, io::{Write, Read}, io, collections::HashMap};
 use crate::{
     server::client::client_profile::Client,
-    commands::Commands,
+    commands::{Commands, split_commands},
 };
 use std::time::Duration;
 use zeroize::Zeroize;
@@ -65,7 +65,122 @@ impl ClientApi {
         }
     }
 
-    pub fn get_clients(&self) {
+    /// The clients connected to a server, without connecting as one.
+    /// Needs the server's admin token, returns the server's reply and the
+    /// clients it listed.
+    pub fn get_clients(host: &str, token: &str) -> Result<(Commands, Vec<HashMap<String, String>>), io::Error> {
+        let params: HashMap<String, String> = [(String::from("action"), String::from("clients"))].iter().cloned().collect();
+        let (reply, listed) = ClientApi::admin_list(host, token, params)?;
+        let clients = listed.into_iter().filter_map(|command| match command {
+            Commands::Client(Some(params)) => Some(params),
+            _ => None,
+        }).collect();
+        Ok((reply, clients))
+    }
+
+    /// Connect as `uuid` just long enough to send one message, either to a
+    /// client or to a room, which is joined first. `secret` is the one the
+    /// server gave `uuid` when it first connected. Returns the server's reply,
+    /// and the secret the server gave `uuid` if this was its first time.
+    pub fn send_message(host: &str, uuid: &str, name: &str, secret: Option<&str>, to: Option<&str>, room: Option<&str>, content: &str) -> Result<(Commands, Option<String>), io::Error> {
+        let mut stream = ClientApi::handshake(host)?;
+        let mut pending = Vec::new();
+        let mut connect: HashMap<String, String> = [(String::from("uuid"), uuid.to_string()), (String::from("name"), name.to_string()), (String::from("host"), String::from("cli"))].iter().cloned().collect();
+        if let Some(secret) = secret {
+            connect.insert(String::from("secret"), secret.to_string());
+        }
+        ClientApi::send(&mut stream, &Commands::Connect(Some(connect)))?;
+
+        // an account connecting for the first time is told its secret in the reply.
+        let issued = match ClientApi::reply(&mut stream, &mut pending)? {
+            Commands::Success(Some(mut params)) => params.remove("secret"),
+            Commands::Success(None) => None,
+            reply => return Ok((reply, None)),
+        };
+
+        let mut params: HashMap<String, String> = [(String::from("content"), content.to_string())].iter().cloned().collect();
+        if let Some(room) = room {
+            let join: HashMap<String, String> = [(String::from("room"), room.to_string())].iter().cloned().collect();
+            ClientApi::send(&mut stream, &Commands::Join(Some(join)))?;
+            match ClientApi::reply(&mut stream, &mut pending)? {
+                Commands::Success(_) => {},
+                reply => return Ok((reply, issued)),
+            }
+            params.insert(String::from("room"), room.to_string());
+        }
+        if let Some(to) = to {
+            params.insert(String::from("to"), to.to_string());
+        }
+
+        ClientApi::send(&mut stream, &Commands::Message(Some(params)))?;
+        let reply = ClientApi::reply(&mut stream, &mut pending)?;
+        let _ = ClientApi::send(&mut stream, &Commands::Disconnect(None));
+        Ok((reply, issued))
+    }
+
+    /// Send an `!admin:` request, authorised by the server's admin token.
+    /// Returns the server's reply and anything it listed before it, such
+    /// as the bans for `action:bans`.
+    pub fn admin_list(host: &str, token: &str, mut params: HashMap<String, String>) -> Result<(Commands, Vec<Commands>), io::Error> {
+        let mut stream = ClientApi::handshake(host)?;
+        params.insert(String::from("token"), token.to_string());
+        ClientApi::send(&mut stream, &Commands::Admin(Some(params)))?;
+        ClientApi::collect(&mut stream, &mut Vec::new())
+    }
+
+    /// Connect and wait for the server to ask who we are.
+    fn handshake(host: &str) -> Result<TcpStream, io::Error> {
+        let addr = host.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "host must be an ip address and port"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_millis(1000))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        match ClientApi::receive(&mut stream, &mut Vec::new())?.first() {
+            Some(Commands::Request(None)) => Ok(stream),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "the data was not expected")),
+        }
+    }
+
+    fn send(stream: &mut TcpStream, command: &Commands) -> Result<(), io::Error> {
+        stream.write_all(command.to_string().as_bytes())?;
+        stream.flush()
+    }
+
+    /// Read the commands in the next packet. A command cut off at the end
+    /// of a read is kept in `pending` until the rest of it arrives.
+    fn receive(stream: &mut TcpStream, pending: &mut Vec<u8>) -> Result<Vec<Commands>, io::Error> {
+        let mut buffer = [0; 1024];
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the server closed the connection"));
+        }
+        pending.extend_from_slice(&buffer[..size]);
+
+        let data = String::from_utf8_lossy(pending).to_string();
+        let mut commands = split_commands(&data);
+        // the last command is only complete if the read didn't fill the buffer.
+        let rest = if size == buffer.len() { commands.pop().unwrap_or("").to_string() } else { String::new() };
+        let commands = commands.into_iter().map(|command| Commands::from(command.to_string())).collect();
+        *pending = rest.into_bytes();
+        Ok(commands)
+    }
 
+    /// Wait for the server to answer the last request, skipping anything
+    /// else it sends in the meantime.
+    fn reply(stream: &mut TcpStream, pending: &mut Vec<u8>) -> Result<Commands, io::Error> {
+        ClientApi::collect(stream, pending).map(|(reply, _)| reply)
+    }
+
+    /// Wait for the server to answer the last request, keeping everything
+    /// else it sends in the meantime.
+    fn collect(stream: &mut TcpStream, pending: &mut Vec<u8>) -> Result<(Commands, Vec<Commands>), io::Error> {
+        let mut others = Vec::new();
+        loop {
+            for command in ClientApi::receive(stream, pending)? {
+                match command {
+                    Commands::Success(_) | Commands::Error(_) => return Ok((command, others)),
+                    command => others.push(command),
+                }
+            }
+        }
     }
 }
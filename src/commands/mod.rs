@@ -48,6 +48,33 @@ pub enum Commands {
     ClientRemove(Option<HashMap<String, String>>),
     Client(Option<HashMap<String, String>>),
 
+    Status(Option<HashMap<String, String>>),
+    Presence(Option<HashMap<String, String>>),
+
+    Message(Option<HashMap<String, String>>),
+    Receipt(Option<HashMap<String, String>>),
+    Mention(Option<HashMap<String, String>>),
+    Typing(Option<HashMap<String, String>>),
+    Edit(Option<HashMap<String, String>>),
+    Delete(Option<HashMap<String, String>>),
+    Thread(Option<HashMap<String, String>>),
+    React(Option<HashMap<String, String>>),
+    Unreact(Option<HashMap<String, String>>),
+    History(Option<HashMap<String, String>>),
+    Search(Option<HashMap<String, String>>),
+
+    FileOffer(Option<HashMap<String, String>>),
+    FileChunk(Option<HashMap<String, String>>),
+    FileGet(Option<HashMap<String, String>>),
+
+    Announce(Option<HashMap<String, String>>),
+    Admin(Option<HashMap<String, String>>),
+    Ban(Option<HashMap<String, String>>),
+    Flag(Option<HashMap<String, String>>),
+
+    Join(Option<HashMap<String, String>>),
+    Leave(Option<HashMap<String, String>>),
+
     Success(Option<HashMap<String, String>>),
     Error(Option<HashMap<String, String>>),
 }
@@ -107,16 +134,38 @@ impl Commands {
 impl PartialEq for Commands {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Commands::Request(params), Commands::Request(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::Info(params), Commands::Info(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::Connect(params), Commands::Connect(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::Disconnect(params), Commands::Disconnect(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::ClientUpdate(params), Commands::ClientUpdate(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::ClientInfo(params), Commands::ClientInfo(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::ClientRemove(params), Commands::ClientRemove(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::Client(params), Commands::Client(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::Success(params), Commands::Success(other_params)) => self.compare_params(&params, &other_params),
-            (Commands::Error(params), Commands::Error(other_params)) => self.compare_params(&params, &other_params),
+            (Commands::Request(params), Commands::Request(other_params)) => self.compare_params(params, other_params),
+            (Commands::Info(params), Commands::Info(other_params)) => self.compare_params(params, other_params),
+            (Commands::Connect(params), Commands::Connect(other_params)) => self.compare_params(params, other_params),
+            (Commands::Disconnect(params), Commands::Disconnect(other_params)) => self.compare_params(params, other_params),
+            (Commands::ClientUpdate(params), Commands::ClientUpdate(other_params)) => self.compare_params(params, other_params),
+            (Commands::ClientInfo(params), Commands::ClientInfo(other_params)) => self.compare_params(params, other_params),
+            (Commands::ClientRemove(params), Commands::ClientRemove(other_params)) => self.compare_params(params, other_params),
+            (Commands::Client(params), Commands::Client(other_params)) => self.compare_params(params, other_params),
+            (Commands::Status(params), Commands::Status(other_params)) => self.compare_params(params, other_params),
+            (Commands::Presence(params), Commands::Presence(other_params)) => self.compare_params(params, other_params),
+            (Commands::Message(params), Commands::Message(other_params)) => self.compare_params(params, other_params),
+            (Commands::Receipt(params), Commands::Receipt(other_params)) => self.compare_params(params, other_params),
+            (Commands::Mention(params), Commands::Mention(other_params)) => self.compare_params(params, other_params),
+            (Commands::Typing(params), Commands::Typing(other_params)) => self.compare_params(params, other_params),
+            (Commands::Edit(params), Commands::Edit(other_params)) => self.compare_params(params, other_params),
+            (Commands::Delete(params), Commands::Delete(other_params)) => self.compare_params(params, other_params),
+            (Commands::Thread(params), Commands::Thread(other_params)) => self.compare_params(params, other_params),
+            (Commands::React(params), Commands::React(other_params)) => self.compare_params(params, other_params),
+            (Commands::Unreact(params), Commands::Unreact(other_params)) => self.compare_params(params, other_params),
+            (Commands::History(params), Commands::History(other_params)) => self.compare_params(params, other_params),
+            (Commands::Search(params), Commands::Search(other_params)) => self.compare_params(params, other_params),
+            (Commands::FileOffer(params), Commands::FileOffer(other_params)) => self.compare_params(params, other_params),
+            (Commands::FileChunk(params), Commands::FileChunk(other_params)) => self.compare_params(params, other_params),
+            (Commands::FileGet(params), Commands::FileGet(other_params)) => self.compare_params(params, other_params),
+            (Commands::Announce(params), Commands::Announce(other_params)) => self.compare_params(params, other_params),
+            (Commands::Admin(params), Commands::Admin(other_params)) => self.compare_params(params, other_params),
+            (Commands::Ban(params), Commands::Ban(other_params)) => self.compare_params(params, other_params),
+            (Commands::Flag(params), Commands::Flag(other_params)) => self.compare_params(params, other_params),
+            (Commands::Join(params), Commands::Join(other_params)) => self.compare_params(params, other_params),
+            (Commands::Leave(params), Commands::Leave(other_params)) => self.compare_params(params, other_params),
+            (Commands::Success(params), Commands::Success(other_params)) => self.compare_params(params, other_params),
+            (Commands::Error(params), Commands::Error(other_params)) => self.compare_params(params, other_params),
             _ => false,
         }
     }
@@ -136,8 +185,30 @@ impl ToString for Commands {
             Commands::Disconnect(arguments) => { ("!disconnect:", arguments) },
             Commands::ClientUpdate(arguments) => { ("!clientUpdate:", arguments) },
             Commands::ClientInfo(arguments) => { ("!clientInfo:", arguments) },
-            Commands::ClientRemove(arguments) => { ("!clientRemove", arguments) }
+            Commands::ClientRemove(arguments) => { ("!clientRemove:", arguments) }
             Commands::Client(arguments) => { ("!client:", arguments) },
+            Commands::Status(arguments) => { ("!status:", arguments) },
+            Commands::Presence(arguments) => { ("!presence:", arguments) },
+            Commands::Message(arguments) => { ("!message:", arguments) },
+            Commands::Receipt(arguments) => { ("!receipt:", arguments) },
+            Commands::Mention(arguments) => { ("!mention:", arguments) },
+            Commands::Typing(arguments) => { ("!typing:", arguments) },
+            Commands::Edit(arguments) => { ("!edit:", arguments) },
+            Commands::Delete(arguments) => { ("!delete:", arguments) },
+            Commands::Thread(arguments) => { ("!thread:", arguments) },
+            Commands::React(arguments) => { ("!react:", arguments) },
+            Commands::Unreact(arguments) => { ("!unreact:", arguments) },
+            Commands::History(arguments) => { ("!history:", arguments) },
+            Commands::Search(arguments) => { ("!search:", arguments) },
+            Commands::FileOffer(arguments) => { ("!fileOffer:", arguments) },
+            Commands::FileChunk(arguments) => { ("!fileChunk:", arguments) },
+            Commands::FileGet(arguments) => { ("!fileGet:", arguments) },
+            Commands::Announce(arguments) => { ("!announce:", arguments) },
+            Commands::Admin(arguments) => { ("!admin:", arguments) },
+            Commands::Ban(arguments) => { ("!ban:", arguments) },
+            Commands::Flag(arguments) => { ("!flag:", arguments) },
+            Commands::Join(arguments) => { ("!join:", arguments) },
+            Commands::Leave(arguments) => { ("!leave:", arguments) },
             Commands::Success(arguments) => { ("!success:", arguments) },
             Commands::Error(arguments) => { ("!error:", arguments) },
         };
@@ -151,8 +222,9 @@ impl ToString for Commands {
                 out_string.push_str(k.as_str());
                 out_string.push_str(":");
 
-                if v.contains(":") {
-                    out_string.push_str(format!("\"{}\"",v.as_str()).as_str())
+                // anything the parser won't accept as a bare value has to be quoted.
+                if v.is_empty() || !v.chars().all(|c| c.is_ascii_alphanumeric() || "@-+[]{}_=/.".contains(c)) {
+                    out_string.push_str(format!("\"{}\"", escape(v)).as_str())
                 } else {
                     out_string.push_str(v.as_str());
                 }
@@ -162,11 +234,77 @@ impl ToString for Commands {
     }
 }
 
+/// Escape a value to go inside quotes. Line breaks are escaped as well as
+/// quotes and backslashes, the stores keep one command per line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo the escaping of a quoted value.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                c => unescaped.extend(c),
+            },
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Split data read from a stream into the commands it contains.
+///
+/// Commands aren't delimited, but an unquoted value can never contain a
+/// `!`, so every `!` outside quotes starts a new command.
+pub fn split_commands(data: &str) -> Vec<&str> {
+    let mut commands = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in data.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '!' if !quoted => {
+                if let Some(start) = start {
+                    commands.push(&data[start..i]);
+                }
+                start = Some(i);
+            },
+            _ => {},
+        }
+    }
+    if let Some(start) = start {
+        commands.push(&data[start..]);
+    }
+    commands
+}
+
 impl FromStr for Commands {
     type Err = CommandParseError;
 
     fn from_str(data: &str) -> std::result::Result<Self, Self::Err> {
-        let regex = Regex::new(r###"(\?|!)([a-zA-z0-9]*):|([a-zA-z]*):([a-zA-Z0-9@\-\+\[\]{}_=/.]+|("(.*?)")+)"###).unwrap();
+        let regex = Regex::new(r###"(\?|!)([a-zA-z0-9]*):|([a-zA-z]*):([a-zA-Z0-9@\-\+\[\]{}_=/.]+|"(?:[^"\\]|\\.)*")"###).unwrap();
         let mut iter = regex.find_iter(data);
         let command_opt = iter.next();
 
@@ -182,9 +320,14 @@ impl FromStr for Commands {
 
         for i in iter {
             let parameter = i.as_str().to_string();
-            let parts:Vec<&str> = parameter.split(":").collect();
-
-            map.insert(parts.index(0).to_string(), parts.index(1).to_string());
+            // split on the first colon only, quoted values may contain more.
+            let parts:Vec<&str> = parameter.splitn(2, ":").collect();
+            let value = match parts.index(1).strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+                Some(quoted) => unescape(quoted),
+                None => parts.index(1).to_string(),
+            };
+
+            map.insert(parts.index(0).to_string(), value);
         }
 
         let params = if map.capacity() > 0 {Some(map)} else { None };
@@ -202,6 +345,33 @@ impl FromStr for Commands {
             "!clientInfo:" => Commands::ClientInfo(params),
             "!client:" => Commands::Client(params),
             "!clientRemove:" => Commands::ClientRemove(params),
+
+            "!status:" => Commands::Status(params),
+            "!presence:" => Commands::Presence(params),
+
+            "!message:" => Commands::Message(params),
+            "!receipt:" => Commands::Receipt(params),
+            "!mention:" => Commands::Mention(params),
+            "!typing:" => Commands::Typing(params),
+            "!edit:" => Commands::Edit(params),
+            "!delete:" => Commands::Delete(params),
+            "!thread:" => Commands::Thread(params),
+            "!react:" => Commands::React(params),
+            "!unreact:" => Commands::Unreact(params),
+            "!history:" => Commands::History(params),
+            "!search:" => Commands::Search(params),
+
+            "!fileOffer:" => Commands::FileOffer(params),
+            "!fileChunk:" => Commands::FileChunk(params),
+            "!fileGet:" => Commands::FileGet(params),
+
+            "!announce:" => Commands::Announce(params),
+            "!admin:" => Commands::Admin(params),
+            "!ban:" => Commands::Ban(params),
+            "!flag:" => Commands::Flag(params),
+
+            "!join:" => Commands::Join(params),
+            "!leave:" => Commands::Leave(params),
             
             "!success:" => Commands::Success(params),
             "!error:" => Commands::Error(params),
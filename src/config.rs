@@ -0,0 +1,518 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs,
+    io,
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use clap::ArgMatches;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::control::DEFAULT_CONTROL_SOCKET;
+use crate::server::{
+    accounts::DEFAULT_ACCOUNTS_PATH,
+    bans::DEFAULT_BANS_PATH,
+    client::client_profile::DEFAULT_IDLE_TIMEOUT,
+    client::outbound::{OverflowPolicy, DEFAULT_OUTBOUND_CAPACITY},
+    client::rate_limit::{self, Rate},
+    filters::{Blocklist, ContentFilters, FilterAction, FilterChain, Links, MaxLength},
+    connections::{ConnectionLimits, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_ADDRESS, DEFAULT_MAX_PENDING_HANDSHAKES, DEFAULT_MAX_PENDING_HANDSHAKES_PER_ADDRESS},
+    history::DEFAULT_HISTORY_PATH,
+    inbox::{Inbox, DEFAULT_INBOX_PATH, DEFAULT_INBOX_QUOTA, DEFAULT_INBOX_EXPIRY},
+    server_profile::Server,
+    settings::LiveSettings,
+    transfers::{DEFAULT_TRANSFER_PATH, DEFAULT_MAX_FILE_SIZE, DEFAULT_TRANSFER_QUOTA},
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read config file {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse config file {}: {}", path, e),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+/// Settings for the server binary, read from a TOML file.
+/// Every field is optional in the file and falls back to its default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub name: String,
+    pub address: String,
+    pub owner: String,
+    pub motd: String,
+    pub log_level: String,
+    pub threads: usize,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub rate_limits: RateLimits,
+    pub filters: Filters,
+    pub storage: Storage,
+    pub admin: Admin,
+}
+
+/// Timeouts in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub idle: u64,
+    pub inbox_expiry: u64,
+    pub handshake: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub inbox_quota: usize,
+    pub max_file_size: u64,
+    pub transfer_quota: u64,
+    pub queue_capacity: usize,
+    pub overflow_policy: String,
+    pub max_connections: usize,
+    pub max_connections_per_address: usize,
+    pub max_pending_handshakes: usize,
+    pub max_pending_handshakes_per_address: usize,
+}
+
+/// How many commands a second each client can send, on average, with
+/// bursts of up to the matching `_burst`. Clients that keep going over
+/// are muted for `mute_duration` seconds after `mute_after` refusals and
+/// disconnected after `disconnect_after`, unless they have stayed under
+/// their limits for `forgive_after` seconds in between.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub commands: f64,
+    pub commands_burst: u32,
+    pub messages: f64,
+    pub messages_burst: u32,
+    pub typing: f64,
+    pub typing_burst: u32,
+    pub updates: f64,
+    pub updates_burst: u32,
+    pub mute_after: u32,
+    pub mute_duration: u64,
+    pub disconnect_after: u32,
+    pub forgive_after: u64,
+}
+
+/// Filters every message is passed through before it is relayed, each
+/// with an action of reject, mask or flag. Rooms listed under `rooms`
+/// use their own filters instead of these.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// Regular expressions, matched ignoring case.
+    pub blocklist: Vec<String>,
+    pub blocklist_action: String,
+    pub links: bool,
+    pub links_action: String,
+    /// Longest message in characters, 0 for no limit.
+    pub max_length: usize,
+    pub max_length_action: String,
+    pub rooms: BTreeMap<String, Filters>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    pub history: String,
+    pub accounts: String,
+    pub bans: String,
+    pub inbox: String,
+    pub transfers: String,
+}
+
+/// Remote administration is disabled while the token is empty, the
+/// local control socket while its path is empty. The token crosses the
+/// network in the clear until TLS is supported, so prefer the control socket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    pub token: String,
+    pub socket: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            name: String::from("Server-01"),
+            address: String::from("0.0.0.0:6000"),
+            owner: String::from("noreply@email.com"),
+            motd: String::new(),
+            log_level: String::from("info"),
+            threads: 16,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            rate_limits: RateLimits::default(),
+            filters: Filters::default(),
+            storage: Storage::default(),
+            admin: Admin::default(),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: DEFAULT_IDLE_TIMEOUT.as_secs(),
+            inbox_expiry: DEFAULT_INBOX_EXPIRY.as_secs(),
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            inbox_quota: DEFAULT_INBOX_QUOTA,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            transfer_quota: DEFAULT_TRANSFER_QUOTA,
+            queue_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            overflow_policy: String::from("coalesce"),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_address: DEFAULT_MAX_CONNECTIONS_PER_ADDRESS,
+            max_pending_handshakes: DEFAULT_MAX_PENDING_HANDSHAKES,
+            max_pending_handshakes_per_address: DEFAULT_MAX_PENDING_HANDSHAKES_PER_ADDRESS,
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let limits = rate_limit::RateLimits::default();
+        RateLimits {
+            commands: limits.commands.rate,
+            commands_burst: limits.commands.burst,
+            messages: limits.messages.rate,
+            messages_burst: limits.messages.burst,
+            typing: limits.typing.rate,
+            typing_burst: limits.typing.burst,
+            updates: limits.updates.rate,
+            updates_burst: limits.updates.burst,
+            mute_after: limits.mute_after,
+            mute_duration: limits.mute_duration.as_secs(),
+            disconnect_after: limits.disconnect_after,
+            forgive_after: limits.forgive_after.as_secs(),
+        }
+    }
+}
+
+impl RateLimits {
+    fn to_limits(&self) -> rate_limit::RateLimits {
+        rate_limit::RateLimits {
+            commands: Rate { rate: self.commands, burst: self.commands_burst },
+            messages: Rate { rate: self.messages, burst: self.messages_burst },
+            typing: Rate { rate: self.typing, burst: self.typing_burst },
+            updates: Rate { rate: self.updates, burst: self.updates_burst },
+            mute_after: self.mute_after,
+            mute_duration: Duration::from_secs(self.mute_duration),
+            disconnect_after: self.disconnect_after,
+            forgive_after: Duration::from_secs(self.forgive_after),
+        }
+    }
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Filters {
+            blocklist: Vec::new(),
+            blocklist_action: String::from("mask"),
+            links: false,
+            links_action: String::from("mask"),
+            max_length: 0,
+            max_length_action: String::from("reject"),
+            rooms: BTreeMap::new(),
+        }
+    }
+}
+
+impl Filters {
+    pub fn to_content_filters(&self) -> Result<ContentFilters, ConfigError> {
+        let mut filters = ContentFilters::default();
+        self.build_chain(filters.chain_mut(None))?;
+        for (room, room_filters) in self.rooms.iter() {
+            if !room_filters.rooms.is_empty() {
+                return Err(ConfigError::Invalid(format!("filters for room {} can't have rooms of their own", room)));
+            }
+            room_filters.build_chain(filters.chain_mut(Some(room)))?;
+        }
+        Ok(filters)
+    }
+
+    fn build_chain(&self, chain: &mut FilterChain) -> Result<(), ConfigError> {
+        fn action(name: &str, value: &str) -> Result<FilterAction, ConfigError> {
+            value.parse().map_err(|_| ConfigError::Invalid(format!("{} '{}' must be one of reject, mask or flag", name, value)))
+        }
+
+        let blocklist_action = action("blocklist_action", &self.blocklist_action)?;
+        let links_action = action("links_action", &self.links_action)?;
+        let max_length_action = action("max_length_action", &self.max_length_action)?;
+
+        if !self.blocklist.is_empty() {
+            let blocklist = Blocklist::new(&self.blocklist).map_err(|e| ConfigError::Invalid(format!("blocklist has an invalid pattern: {}", e)))?;
+            chain.add(blocklist, blocklist_action);
+        }
+        if self.links {
+            chain.add(Links::default(), links_action);
+        }
+        // checked last, masking a link can make a message longer.
+        if self.max_length > 0 {
+            chain.add(MaxLength(self.max_length), max_length_action);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Admin {
+            token: String::new(),
+            socket: DEFAULT_CONTROL_SOCKET.to_string(),
+        }
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            history: DEFAULT_HISTORY_PATH.to_string(),
+            accounts: DEFAULT_ACCOUNTS_PATH.to_string(),
+            bans: DEFAULT_BANS_PATH.to_string(),
+            inbox: DEFAULT_INBOX_PATH.to_string(),
+            transfers: DEFAULT_TRANSFER_PATH.to_string(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let data = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+        ServerConfig::parse(path, &data)
+    }
+
+    /// Parse a config file, `path` is only used in error messages.
+    pub fn parse(path: &str, data: &str) -> Result<Self, ConfigError> {
+        toml::from_str(data).map_err(|e| ConfigError::Parse(path.to_string(), e))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config can always be serialised")
+    }
+
+    /// Replace settings with any that were given on the command line.
+    pub fn apply_args(&mut self, args: &ArgMatches) -> Result<(), ConfigError> {
+        fn number<T: std::str::FromStr>(args: &ArgMatches, flag: &str, field: &mut T) -> Result<(), ConfigError> {
+            if let Some(value) = args.value_of(flag) {
+                *field = value.parse().map_err(|_| ConfigError::Invalid(format!("--{} must be a number, got '{}'", flag, value)))?;
+            }
+            Ok(())
+        }
+        fn text(args: &ArgMatches, flag: &str, field: &mut String) {
+            if let Some(value) = args.value_of(flag) {
+                *field = value.to_string();
+            }
+        }
+
+        text(args, "name", &mut self.name);
+        text(args, "address", &mut self.address);
+        text(args, "owner", &mut self.owner);
+        text(args, "motd", &mut self.motd);
+        text(args, "log-level", &mut self.log_level);
+        number(args, "threads", &mut self.threads)?;
+        number(args, "idle-timeout", &mut self.timeouts.idle)?;
+        number(args, "inbox-expiry", &mut self.timeouts.inbox_expiry)?;
+        number(args, "handshake-timeout", &mut self.timeouts.handshake)?;
+        number(args, "inbox-quota", &mut self.limits.inbox_quota)?;
+        number(args, "max-file-size", &mut self.limits.max_file_size)?;
+        number(args, "transfer-quota", &mut self.limits.transfer_quota)?;
+        number(args, "queue-capacity", &mut self.limits.queue_capacity)?;
+        text(args, "overflow-policy", &mut self.limits.overflow_policy);
+        number(args, "max-connections", &mut self.limits.max_connections)?;
+        number(args, "max-connections-per-address", &mut self.limits.max_connections_per_address)?;
+        text(args, "history-path", &mut self.storage.history);
+        text(args, "accounts-path", &mut self.storage.accounts);
+        text(args, "bans-path", &mut self.storage.bans);
+        text(args, "inbox-path", &mut self.storage.inbox);
+        text(args, "transfer-path", &mut self.storage.transfers);
+        if let Some(path) = args.value_of("admin-token-file") {
+            self.admin.token = fs::read_to_string(path).map_err(|e| ConfigError::Invalid(format!("can't read --admin-token-file {}: {}", path, e)))?.trim().to_string();
+        }
+        text(args, "control-socket", &mut self.admin.socket);
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_string()));
+
+        if self.name.trim().is_empty() {
+            return invalid("name can't be empty");
+        }
+        if self.address.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(format!("address '{}' is not an ip address and port, e.g. 0.0.0.0:6000", self.address)));
+        }
+        if self.owner.trim().is_empty() {
+            return invalid("owner can't be empty");
+        }
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            return Err(ConfigError::Invalid(format!("log_level '{}' must be one of off, error, warn, info, debug or trace", self.log_level)));
+        }
+        if self.threads == 0 {
+            return invalid("threads must be at least 1");
+        }
+        if self.timeouts.idle == 0 || self.timeouts.inbox_expiry == 0 || self.timeouts.handshake == 0 {
+            return invalid("timeouts must be at least 1 second");
+        }
+        if self.limits.inbox_quota == 0 || self.limits.max_file_size == 0 || self.limits.queue_capacity == 0 {
+            return invalid("inbox_quota, max_file_size and queue_capacity must be at least 1");
+        }
+        if self.limits.max_connections == 0 || self.limits.max_connections_per_address == 0 || self.limits.max_pending_handshakes == 0 || self.limits.max_pending_handshakes_per_address == 0 {
+            return invalid("max_connections, max_connections_per_address, max_pending_handshakes and max_pending_handshakes_per_address must be at least 1");
+        }
+        if self.limits.transfer_quota < self.limits.max_file_size {
+            return invalid("transfer_quota must be at least max_file_size");
+        }
+        if self.limits.overflow_policy.parse::<OverflowPolicy>().is_err() {
+            return Err(ConfigError::Invalid(format!("overflow_policy '{}' must be one of drop-oldest, coalesce or disconnect", self.limits.overflow_policy)));
+        }
+        let rates = &self.rate_limits;
+        if [rates.commands, rates.messages, rates.typing, rates.updates].iter().any(|rate| rate.is_nan() || *rate <= 0.0) {
+            return invalid("rate limits must be more than 0 commands a second");
+        }
+        if [rates.commands_burst, rates.messages_burst, rates.typing_burst, rates.updates_burst, rates.mute_after, rates.disconnect_after].contains(&0) {
+            return invalid("rate limit bursts, mute_after and disconnect_after must be at least 1");
+        }
+        self.filters.to_content_filters()?;
+        let storage = &self.storage;
+        if [&storage.history, &storage.accounts, &storage.bans, &storage.inbox, &storage.transfers].iter().any(|path| path.is_empty()) {
+            return invalid("storage paths can't be empty");
+        }
+        Ok(())
+    }
+
+    /// The settings that can be applied to a running server.
+    pub fn live_settings(&self) -> LiveSettings {
+        LiveSettings {
+            motd: self.motd.clone(),
+            idle_timeout: Duration::from_secs(self.timeouts.idle),
+            inbox_quota: self.limits.inbox_quota,
+            inbox_expiry: Duration::from_secs(self.timeouts.inbox_expiry),
+            max_file_size: self.limits.max_file_size,
+            transfer_quota: self.limits.transfer_quota,
+            queue_capacity: self.limits.queue_capacity,
+            overflow_policy: self.limits.overflow_policy.parse().unwrap_or(OverflowPolicy::Coalesce),
+            rate_limits: self.rate_limits.to_limits(),
+            connection_limits: self.connection_limits(),
+        }
+    }
+
+    fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: self.limits.max_connections,
+            max_per_address: self.limits.max_connections_per_address,
+            max_pending: self.limits.max_pending_handshakes,
+            max_pending_per_address: self.limits.max_pending_handshakes_per_address,
+            handshake_timeout: Duration::from_secs(self.timeouts.handshake),
+        }
+    }
+
+    /// The settings that differ from `other` but only take effect after a restart.
+    pub fn restart_required(&self, other: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.name != other.name { changed.push("name"); }
+        if self.address != other.address { changed.push("address"); }
+        if self.owner != other.owner { changed.push("owner"); }
+        if self.threads != other.threads { changed.push("threads"); }
+        if self.storage != other.storage { changed.push("storage"); }
+        if self.filters != other.filters { changed.push("filters"); }
+        if self.admin != other.admin { changed.push("admin"); }
+        changed
+    }
+
+    pub fn apply_log_level(&self) {
+        log::set_max_level(self.log_level.parse().unwrap_or(LevelFilter::Info));
+    }
+
+    /// Create a server from a validated config.
+    pub fn build(&self) -> Server {
+        let mut server = Server::new(&self.name, &self.address, &self.owner);
+        server.set_motd(&self.motd);
+        server.set_thread_pool_size(self.threads);
+        server.set_idle_timeout(Duration::from_secs(self.timeouts.idle));
+        server.set_inbox(Inbox::new(&self.storage.inbox, self.limits.inbox_quota, Duration::from_secs(self.timeouts.inbox_expiry)));
+        server.set_history_path(&self.storage.history);
+        server.set_accounts_path(&self.storage.accounts);
+        server.set_bans_path(&self.storage.bans);
+        server.set_transfer_limits(&self.storage.transfers, self.limits.max_file_size, self.limits.transfer_quota);
+        server.set_outbound_queue(self.limits.queue_capacity, self.limits.overflow_policy.parse().unwrap_or(OverflowPolicy::Coalesce));
+        server.set_rate_limits(self.rate_limits.to_limits());
+        server.set_connection_limits(self.connection_limits());
+        server.set_content_filters(self.filters.to_content_filters().unwrap_or_default());
+        server.set_admin_token(&self.admin.token);
+        server
+    }
+}
+
+/// Re-reads the config file of a running server, keeping any settings that
+/// were overridden on the command line.
+#[derive(Debug)]
+pub struct Reloader {
+    path: Option<String>,
+    args: ArgMatches,
+    current: Mutex<ServerConfig>,
+}
+
+impl Reloader {
+    pub fn new(path: Option<&str>, args: ArgMatches, current: ServerConfig) -> Self {
+        Reloader {
+            path: path.map(String::from),
+            args,
+            current: Mutex::new(current),
+        }
+    }
+
+    /// Apply the config file to the server. Returns the settings that
+    /// changed but need a restart, these keep their old values until then.
+    pub fn reload(&self, server: &Server) -> Result<Vec<&'static str>, ConfigError> {
+        let path = self.path.as_ref().ok_or_else(|| ConfigError::Invalid(String::from("the server wasn't started with a config file")))?;
+
+        let mut config = ServerConfig::load(path)?;
+        config.apply_args(&self.args)?;
+        config.validate()?;
+
+        let mut current = self.current.lock().unwrap();
+        let restart_required = current.restart_required(&config);
+        config.apply_log_level();
+        server.apply_settings(config.live_settings());
+        // the settings that need a restart keep their running values, so
+        // they are still reported on the next reload if they stay changed.
+        current.motd = config.motd;
+        current.log_level = config.log_level;
+        current.timeouts = config.timeouts;
+        current.limits = config.limits;
+        current.rate_limits = config.rate_limits;
+        Ok(restart_required)
+    }
+
+    /// A one line summary of a reload for the operator.
+    pub fn describe(result: &Result<Vec<&'static str>, ConfigError>) -> String {
+        match result {
+            Ok(changed) if changed.is_empty() => String::from("config reloaded"),
+            Ok(changed) => format!("config reloaded, restart to apply: {}", changed.join(", ")),
+            Err(e) => format!("config not reloaded, {}", e),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+};
+
+use crate::{
+    commands::Commands,
+    config::Reloader,
+    server::{admin::admin_message, server_profile::Server},
+};
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "data/control.sock";
+
+/// Answer one line sent to the control socket. Requests use the same
+/// `!admin:` command as remote administration, without the token, plus
+/// `list`, `stats` and `reload` which only work locally. Lists are sent
+/// one command per line, ending with `!success:`.
+pub fn respond(line: &str, server: &Server, reloader: &Reloader) -> String {
+    let params = match Commands::from(line.trim().to_string()) {
+        Commands::Admin(Some(params)) => params,
+        _ => return error("expected !admin: action:<action>"),
+    };
+
+    match params.get("action").map(String::as_str) {
+        Some("list") => match server.list_clients() {
+            Some(clients) => {
+                let mut reply: String = clients.iter().map(|client| {
+                    let params: HashMap<String, String> = [
+                        (String::from("uuid"), client.uuid.clone()),
+                        (String::from("name"), client.name.clone()),
+                        (String::from("address"), client.address.clone()),
+                        (String::from("heartbeat"), client.last_heartbeat.elapsed().as_secs().to_string()),
+                    ].iter().cloned().collect();
+                    Commands::Client(Some(params)).to_string() + "\n"
+                }).collect();
+                reply.push_str(&Commands::Success(None).to_string());
+                reply
+            },
+            None => error("the server isn't running"),
+        },
+        Some("bans") => match server.list_bans() {
+            Some(bans) => {
+                let mut reply: String = bans.iter().map(|ban| Commands::Ban(Some(ban.to_params())).to_string() + "\n").collect();
+                reply.push_str(&Commands::Success(None).to_string());
+                reply
+            },
+            None => error("the server isn't running"),
+        },
+        Some("stats") => match server.get_stats() {
+            Some(stats) => Commands::Success(Some(stats)).to_string(),
+            None => error("the server isn't running"),
+        },
+        Some("reload") => {
+            let result = reloader.reload(server);
+            match result {
+                Ok(_) => {
+                    let params: HashMap<String, String> = [(String::from("result"), Reloader::describe(&result))].iter().cloned().collect();
+                    Commands::Success(Some(params)).to_string()
+                },
+                Err(_) => error(&Reloader::describe(&result)),
+            }
+        },
+        _ => match admin_message(&params) {
+            Ok(message) => {
+                server.send(message);
+                Commands::Success(None).to_string()
+            },
+            Err(reason) => error(reason),
+        },
+    }
+}
+
+fn error(reason: &str) -> String {
+    let params: HashMap<String, String> = [(String::from("reason"), reason.to_string())].iter().cloned().collect();
+    Commands::Error(Some(params)).to_string()
+}
+
+/// Accept admin commands on a Unix socket at `path`, one per line. Only the
+/// server's user can connect, anyone who can is trusted completely.
+/// Fails if another server is still listening at `path`.
+#[cfg(unix)]
+pub fn listen(path: &str, server: Arc<Server>, reloader: Arc<Reloader>) -> std::io::Result<()> {
+    use std::{
+        fs,
+        io::{self, BufRead, BufReader, Write},
+        os::unix::{
+            fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+        path::Path,
+    };
+
+    let path = Path::new(path);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "something other than a socket is in the way"));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on it"));
+        }
+        // a socket left behind by a server that didn't shut down cleanly.
+        fs::remove_file(path)?;
+    }
+
+    // bound in a directory only we can enter and moved into place once
+    // locked down, so nobody else can connect while the mode is being set.
+    let private = dir.join(format!(".control-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("control.sock");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    let listener = listener?;
+
+    std::thread::Builder::new().name("Control Thread".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("control: failed to accept connection: {}", e);
+                    continue;
+                },
+            };
+            let reader = match stream.try_clone() {
+                Ok(reader) => BufReader::new(reader),
+                Err(_) => continue,
+            };
+
+            // each connection gets its own thread so an idle one can't hold up the rest.
+            let server = server.clone();
+            let reloader = reloader.clone();
+            std::thread::spawn(move || {
+                for line in reader.lines().map_while(Result::ok) {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let reply = respond(&line, &server, &reloader);
+                    if stream.write_all((reply + "\n").as_bytes()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &str, _server: Arc<Server>, _reloader: Arc<Reloader>) -> std::io::Result<()> {
+    Ok(())
+}
@@ -1,5 +1,8 @@
+mod cli;
 mod client_api;
 mod commands;
+mod config;
+mod control;
 mod server;
 mod lib;
 
@@ -7,38 +10,113 @@ use cursive::{
     Cursive,
     menu::*,
     event::Key,
-    views::{ Dialog, TextView, LinearLayout, ListView, ResizedView, Panel },
+    views::{ Dialog, EditView, TextView, LinearLayout, ResizedView, Panel, ScrollView, SelectView },
     CursiveExt,
+    CbSink,
     align::Align,
-    view::SizeConstraint,
+    view::{Nameable, ScrollStrategy, SizeConstraint},
 };
 //use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
 use crossterm::ErrorKind;
 use log::info;
 use clap::{App, Arg};
 
+use crate::config::{Reloader, ServerConfig};
+use crate::server::events::{ClientSummary, ServerEvent};
 use crate::server::server_profile::Server;
 
+/// How many lines the control panel's activity log keeps.
+const ACTIVITY_LOG_LINES: usize = 200;
+
 fn main() -> Result<(), ErrorKind> {
-    let args = App::new("--rust chat server--")
+    let host = || Arg::new("host").required(true).about("The server's address, e.g. 127.0.0.1:6000");
+    let token_file = || Arg::new("token-file").long("token-file").value_name("FILE").about("A file holding the server's admin token, read from RUST_CHAT_ADMIN_TOKEN otherwise");
+    let args = server_args(App::new("--rust chat server--"))
         .version("0.1.5")
         .author("Mitchel Hardie <mitch161>, Michael Bailey <michael-bailey>")
         .about("this is a chat server developed in rust, depending on the version one of two implementations will be used")
-        .arg(Arg::with_name("graphical")
-            .short('g')
-            .takes_value(false)
-            .about("Enables graphical mode"))
+        .subcommand(server_args(App::new("serve").about("Runs the server, the default when no command is given")))
+        .subcommand(App::new("info").about("Shows a server's name, owner and message of the day").arg(host()))
+        .subcommand(App::new("clients").about("Lists the clients connected to a server, needs the server's admin token").arg(host()).arg(token_file()))
+        .subcommand(App::new("send").about("Sends a message to a client or room")
+            .arg(host())
+            .arg(Arg::new("message").required(true).about("The message to send"))
+            .arg(Arg::new("to").long("to").value_name("UUID").about("Sends the message to a client"))
+            .arg(Arg::new("room").long("room").value_name("ROOM").about("Joins a room and sends the message to it"))
+            .arg(Arg::new("uuid").long("uuid").value_name("UUID").default_value("cli").about("The uuid to send as"))
+            .arg(Arg::new("as").long("as").value_name("NAME").default_value("cli").about("The name to send as"))
+            .arg(Arg::new("secret-file").long("secret-file").value_name("FILE").about("Where the secret for the uuid is kept, it is saved here the first time the uuid connects")))
+        .subcommand(App::new("admin").about("Manages a running server, needs the server's admin token")
+            .arg(host())
+            .arg(token_file())
+            .subcommand(App::new("kick").about("Disconnects a client")
+                .arg(Arg::new("uuid").required(true))
+                .arg(Arg::new("reason").long("reason").value_name("REASON")))
+            .subcommand(ban_args(App::new("ban").about("Disconnects a client and stops it, its name or its network connecting again"))
+                .arg(Arg::new("reason").long("reason").value_name("REASON"))
+                .arg(Arg::new("duration").long("duration").value_name("SECONDS").about("Lifts the ban after this long")))
+            .subcommand(ban_args(App::new("unban").about("Lifts a ban")))
+            .subcommand(App::new("bans").about("Lists the bans in force"))
+            .subcommand(App::new("mute").about("Stops a client sending messages to a room")
+                .arg(Arg::new("room").required(true))
+                .arg(Arg::new("uuid").required(true))
+                .arg(Arg::new("duration").long("duration").value_name("SECONDS").about("Lifts the mute after this long")))
+            .subcommand(App::new("unmute").about("Lets a client send messages to a room again")
+                .arg(Arg::new("room").required(true))
+                .arg(Arg::new("uuid").required(true)))
+            .subcommand(App::new("announce").about("Sends an announcement to every client")
+                .arg(Arg::new("content").required(true)))
+            .subcommand(App::new("role").about("Gives an account a role")
+                .arg(Arg::new("uuid").required(true))
+                .arg(Arg::new("role").required(true).about("One of owner, admin, moderator or user")))
+            .subcommand(App::new("shutdown").about("Stops the server")))
         .get_matches();
 
+    let args = match args.subcommand() {
+        Some(("serve", serve_args)) => serve_args.clone(),
+        Some((subcommand, subcommand_args)) => {
+            if let Err(e) = cli::run(subcommand, subcommand_args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        },
+        None => args,
+    };
+
+    if args.is_present("print-default-config") {
+        print!("{}", ServerConfig::default().to_toml());
+        return Ok(());
+    }
+
+    let config = args.value_of("config")
+        .map(ServerConfig::load)
+        .unwrap_or_else(|| Ok(ServerConfig::default()))
+        .and_then(|mut config| config.apply_args(&args).map(|_| config))
+        .and_then(|config| config.validate().map(|_| config));
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    let reloader = Arc::new(Reloader::new(args.value_of("config"), args.clone(), config.clone()));
+
     if args.is_present("graphical") {
-        let server = Server::new("Server-01", "0.0.0.0:6000", "noreply@email.com");
+        let server = config.build();
         let server_arc = Arc::new(server);
         let s1 = server_arc.clone();
         let s2 = s1.clone();
+        let s3 = s1.clone();
+        let s4 = s1.clone();
+        watch_for_reload(server_arc.clone(), reloader.clone());
+        open_control_socket(&config, server_arc.clone(), reloader.clone());
 
         cursive::logger::init();
+        config.apply_log_level();
 
         info!("Main: init display");
         let mut display = Cursive::default();
@@ -54,30 +132,128 @@ fn main() -> Result<(), ErrorKind> {
                          MenuTree::new()
                              .leaf("about",
                                    |s| s.add_layer(about()))
+                             .leaf("Announce", move |s| s.add_layer(announce_dialog(s4.clone())))
                              .delimiter()
                              .leaf("quit", |s| s.quit()))
             .add_subtree("File",
                          MenuTree::new()
                              .leaf("Start", move |_s| {let _ = s1.start();})
                              .leaf("Stop", move |_s| {let _ = s2.stop();})
+                             .leaf("Reload config", move |s| {
+                                 let message = Reloader::describe(&reloader.reload(&s3));
+                                 s.add_layer(Dialog::info(message));
+                             })
                              .delimiter()
                              .leaf("Debug", |s| {s.toggle_debug_console();}));
         info!("Main: entering loop");
-        display.add_layer(control_panel());
+        display.set_user_data(PanelState::new(&config.address));
+        display.add_layer(control_panel(server_arc.clone()));
+        refresh_status(&mut display);
+        watch_server(&server_arc, display.cb_sink().clone());
         display.run();
         Ok(())
     } else {
-        let server = Server::new("Server-01", "0.0.0.0:6000", "noreply@email.com");
+        let server = Arc::new(config.build());
+        config.apply_log_level();
 
+        let events = server.subscribe();
+        open_control_socket(&config, server.clone(), reloader.clone());
         server.start()?;
-        loop {std::thread::sleep(Duration::from_secs(1));}
+        watch_for_reload(server, reloader);
+
+        // run until the server is shut down from the control socket or remotely.
+        for event in events.iter() {
+            if let ServerEvent::Stopped = event {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Who a ban or unban applies to, exactly one of these is needed.
+fn ban_args(app: App) -> App {
+    app.arg(Arg::new("uuid").about("The client to ban"))
+        .arg(Arg::new("username").long("username").value_name("NAME").conflicts_with("uuid").about("Bans a username instead"))
+        .arg(Arg::new("address").long("address").value_name("ADDRESS").conflicts_with_all(&["uuid", "username"]).about("Bans an ip address or network, e.g. 10.0.0.0/8"))
+}
+
+/// The flags for running the server, shared by `serve` and running with no command.
+fn server_args(app: App) -> App {
+    app.arg(Arg::new("graphical")
+            .short('g')
+            .takes_value(false)
+            .about("Enables graphical mode"))
+        .arg(Arg::new("config")
+            .short('c')
+            .long("config")
+            .value_name("FILE")
+            .about("Reads settings from a TOML config file"))
+        .arg(Arg::new("print-default-config")
+            .long("print-default-config")
+            .about("Prints the default config file and exits"))
+        .arg(Arg::new("name").long("name").value_name("NAME").about("Overrides the server name"))
+        .arg(Arg::new("address").long("address").value_name("ADDRESS").about("Overrides the address to listen on"))
+        .arg(Arg::new("owner").long("owner").value_name("OWNER").about("Overrides the server owner"))
+        .arg(Arg::new("motd").long("motd").value_name("MESSAGE").about("Overrides the message of the day"))
+        .arg(Arg::new("log-level").long("log-level").value_name("LEVEL").about("Overrides the log level"))
+        .arg(Arg::new("threads").long("threads").value_name("COUNT").about("Overrides the thread pool size"))
+        .arg(Arg::new("idle-timeout").long("idle-timeout").value_name("SECONDS").about("Overrides how long clients can be idle before they are away"))
+        .arg(Arg::new("inbox-expiry").long("inbox-expiry").value_name("SECONDS").about("Overrides how long offline messages are kept"))
+        .arg(Arg::new("handshake-timeout").long("handshake-timeout").value_name("SECONDS").about("Overrides how long new connections have to shake hands"))
+        .arg(Arg::new("inbox-quota").long("inbox-quota").value_name("COUNT").about("Overrides how many offline messages are kept per user"))
+        .arg(Arg::new("max-file-size").long("max-file-size").value_name("BYTES").about("Overrides the largest file that can be sent"))
+        .arg(Arg::new("transfer-quota").long("transfer-quota").value_name("BYTES").about("Overrides the space all file transfers may use"))
+        .arg(Arg::new("queue-capacity").long("queue-capacity").value_name("COUNT").about("Overrides how many commands can be queued per client"))
+        .arg(Arg::new("overflow-policy").long("overflow-policy").value_name("POLICY").about("Overrides what happens when a client's queue is full"))
+        .arg(Arg::new("max-connections").long("max-connections").value_name("COUNT").about("Overrides how many connections the server accepts"))
+        .arg(Arg::new("max-connections-per-address").long("max-connections-per-address").value_name("COUNT").about("Overrides how many connections one address can have"))
+        .arg(Arg::new("history-path").long("history-path").value_name("FILE").about("Overrides where message history is kept"))
+        .arg(Arg::new("accounts-path").long("accounts-path").value_name("FILE").about("Overrides where accounts are kept"))
+        .arg(Arg::new("bans-path").long("bans-path").value_name("FILE").about("Overrides where bans are kept"))
+        .arg(Arg::new("inbox-path").long("inbox-path").value_name("DIR").about("Overrides where offline messages are kept"))
+        .arg(Arg::new("transfer-path").long("transfer-path").value_name("DIR").about("Overrides where file transfers are kept"))
+        .arg(Arg::new("admin-token-file").long("admin-token-file").value_name("FILE").about("Overrides the token needed for remote administration with the contents of a file"))
+        .arg(Arg::new("control-socket").long("control-socket").value_name("FILE").about("Overrides where the local control socket is created"))
+}
+
+/// Open the control socket, or exit if it can't be, such as when another
+/// server is already running with it.
+fn open_control_socket(config: &ServerConfig, server: Arc<Server>, reloader: Arc<Reloader>) {
+    if config.admin.socket.is_empty() {
+        return;
     }
+    if let Err(e) = control::listen(&config.admin.socket, server, reloader) {
+        eprintln!("can't open the control socket {}: {}", config.admin.socket, e);
+        std::process::exit(1);
+    }
+}
+
+/// Reload the config file whenever the process receives SIGHUP.
+#[cfg(unix)]
+fn watch_for_reload(server: Arc<Server>, reloader: Arc<Reloader>) {
+    let signals = match signal_hook::iterator::Signals::new([signal_hook::SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Main: can't listen for SIGHUP, reloading is disabled: {}", e);
+            return;
+        },
+    };
+
+    let _ = std::thread::Builder::new().name("Reload Thread".to_string()).spawn(move || {
+        for _ in signals.forever() {
+            println!("Main: {}", Reloader::describe(&reloader.reload(&server)));
+        }
+    });
 }
 
+#[cfg(not(unix))]
+fn watch_for_reload(_server: Arc<Server>, _reloader: Arc<Reloader>) {}
+
 fn about() -> Dialog {
     Dialog::new()
         .content(TextView::new("Rust-Chat-Server\nmade by\n Mitchell Hardie\nMichael Bailey\nMit Licence")
-        ).button("Close", |s| {let _ = s.pop_layer(); s.add_layer(control_panel())} )
+        ).button("Close", |s| {let _ = s.pop_layer();} )
 }
 
 #[allow(dead_code)]
@@ -92,33 +268,333 @@ fn launch_screen() -> Dialog {
         .button("ok", |s| {s.pop_layer();})
 }
 
-fn control_panel() -> ResizedView<Panel<LinearLayout>> {
-
+fn control_panel(server: Arc<Server>) -> ResizedView<Panel<LinearLayout>> {
     let mut root = LinearLayout::horizontal();
     let mut left = LinearLayout::vertical();
-    let mut right = ListView::new();
-    right.add_child("test", TextView::new(""));
-    right.add_child("test", TextView::new(""));
-    right.add_delimiter();
-    right.add_child("test", TextView::new(""));
-    right.add_child("test", TextView::new(""));
 
-    left.add_child(TextView::new("Hello world"));
+    left.add_child(Panel::new(TextView::new("").with_name("status")).title("Status"));
+    left.add_child(ResizedView::new(SizeConstraint::Full, SizeConstraint::Full, Panel::new(
+        ScrollView::new(TextView::new("").with_name("activity"))
+            .scroll_strategy(ScrollStrategy::StickToBottom)
+    ).title("Activity")));
+
+    let clients = SelectView::<String>::new()
+        .on_submit(move |s, uuid: &String| s.add_layer(client_actions(server.clone(), uuid)));
+
+    root.add_child(ResizedView::new(SizeConstraint::Full, SizeConstraint::Full, left));
+    root.add_child(ResizedView::new(SizeConstraint::Full, SizeConstraint::Full, Panel::new(
+        ScrollView::new(clients.with_name("clients"))
+    ).title("Clients")));
+    ResizedView::new(SizeConstraint::Full, SizeConstraint::Full, Panel::new(root))
+}
+
+/// Actions for the client selected in the control panel.
+fn client_actions(server: Arc<Server>, uuid: &str) -> Dialog {
+    let kick_server = server.clone();
+    let kick_uuid = uuid.to_string();
+    let ban_server = server.clone();
+    let ban_uuid = uuid.to_string();
+    let ban_address_uuid = uuid.to_string();
+
+    Dialog::text(format!("Client {}", uuid))
+        .title("Client")
+        .button("Kick", move |s| {
+            let server = kick_server.clone();
+            let uuid = kick_uuid.clone();
+            s.pop_layer();
+            s.add_layer(reason_dialog("Kick", move |reason| server.kick(&uuid, reason)));
+        })
+        .button("Ban", move |s| {
+            let server = ban_server.clone();
+            let uuid = ban_uuid.clone();
+            s.pop_layer();
+            s.add_layer(reason_dialog("Ban", move |reason| server.ban(&uuid, reason, false)));
+        })
+        // everyone connecting from the same address is shut out too.
+        .button("Ban with address", move |s| {
+            let server = server.clone();
+            let uuid = ban_address_uuid.clone();
+            s.pop_layer();
+            s.add_layer(reason_dialog("Ban", move |reason| server.ban(&uuid, reason, true)));
+        })
+        .dismiss_button("Cancel")
+}
+
+/// Ask for the reason for a kick or ban, then carry it out.
+fn reason_dialog<F: Fn(&str) + 'static>(action: &str, act: F) -> Dialog {
+    Dialog::new()
+        .title(format!("{} reason", action))
+        .content(EditView::new().with_name("reason"))
+        .button(action, move |s| {
+            let reason = s.call_on_name("reason", |view: &mut EditView| view.get_content()).unwrap();
+            act(&reason);
+            s.pop_layer();
+        })
+        .dismiss_button("Cancel")
+}
+
+fn announce_dialog(server: Arc<Server>) -> Dialog {
+    Dialog::new()
+        .title("Announcement")
+        .content(ResizedView::with_min_width(40, EditView::new().with_name("announcement")))
+        .button("Send", move |s| {
+            let content = s.call_on_name("announcement", |view: &mut EditView| view.get_content()).unwrap();
+            if !content.is_empty() {
+                server.announce(&content);
+            }
+            s.pop_layer();
+        })
+        .dismiss_button("Cancel")
+}
+
+/// What the control panel remembers about the server between events.
+struct PanelState {
+    address: String,
+    started: Option<Instant>,
+    activity: Vec<String>,
+}
+
+impl PanelState {
+    fn new(address: &str) -> Self {
+        PanelState { address: address.to_string(), started: None, activity: Vec::new() }
+    }
+}
+
+/// Forward the server's events to the display. The server thread only
+/// ever talks to the forwarding thread, which hands each event to the
+/// display through its callback sink.
+fn watch_server(server: &Server, sink: CbSink) {
+    let events = server.subscribe();
+    let _ = std::thread::Builder::new().name("Display Thread".to_string()).spawn(move || {
+        for event in events.iter() {
+            if sink.send(Box::new(move |s| show_event(s, event))).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn show_event(s: &mut Cursive, event: ServerEvent) {
+    match event {
+        ServerEvent::Started(address) => {
+            s.with_user_data(|state: &mut PanelState| {
+                state.address = address;
+                state.started = Some(Instant::now());
+            });
+            log_activity(s, String::from("server started"));
+        },
+        ServerEvent::Stopped => {
+            s.with_user_data(|state: &mut PanelState| state.started = None);
+            log_activity(s, String::from("server stopped"));
+            show_clients(s, Vec::new());
+        },
+        ServerEvent::Clients(clients) => show_clients(s, clients),
+        ServerEvent::Activity(line) => log_activity(s, line),
+    }
+    refresh_status(s);
+}
+
+fn refresh_status(s: &mut Cursive) {
+    let status = s.with_user_data(|state: &mut PanelState| match state.started {
+        Some(started) => format!("running on {}\nuptime {}", state.address, format_duration(started.elapsed())),
+        None => format!("stopped\naddress {}", state.address),
+    });
+    if let Some(status) = status {
+        s.call_on_name("status", |view: &mut TextView| view.set_content(status));
+    }
+}
+
+fn log_activity(s: &mut Cursive, line: String) {
+    let activity = s.with_user_data(|state: &mut PanelState| {
+        state.activity.push(line);
+        let excess = state.activity.len().saturating_sub(ACTIVITY_LOG_LINES);
+        state.activity.drain(..excess);
+        state.activity.join("\n")
+    });
+    if let Some(activity) = activity {
+        s.call_on_name("activity", |view: &mut TextView| view.set_content(activity));
+    }
+}
+
+fn show_clients(s: &mut Cursive, clients: Vec<ClientSummary>) {
+    s.call_on_name("clients", |view: &mut SelectView<String>| {
+        let selected = view.selection();
+        view.clear();
+        for client in clients {
+            let label = format!("{} {} {} ({}s ago)", client.name, client.uuid, client.address, client.last_heartbeat.elapsed().as_secs());
+            view.add_item(label, client.uuid);
+        }
+        if let Some(position) = selected.and_then(|uuid| view.iter().position(|(_label, value)| *value == *uuid)) {
+            view.set_selection(position);
+        }
+    });
+}
 
-    root.add_child(ResizedView::new(SizeConstraint::Full, SizeConstraint::Full, Panel::new(left)));
-    root.add_child(ResizedView::new(SizeConstraint::Full, SizeConstraint::Full, Panel::new(right)));
-    ResizedView::new(SizeConstraint::Fixed(60), SizeConstraint::Fixed(18), Panel::new(root))
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
 }
 
 // MARK: - general testing zone
 #[cfg(test)]
 mod tests {
+    use crate::server::events::ServerEvent;
     use crate::server::server_profile::Server;
+    use crate::server::inbox::{Inbox, DEFAULT_INBOX_EXPIRY, DEFAULT_INBOX_QUOTA};
+    use crate::server::history::MAX_REACTIONS_PER_MESSAGE;
+    use crate::server::admin::token_matches;
+    use crate::server::transfers::{hex_encode, Transfers, CHUNK_SIZE, DEFAULT_MAX_FILE_SIZE, DEFAULT_TRANSFER_QUOTA};
+    use openssl::sha::sha256;
+    use crate::server::roles::Role;
+    use crate::server::accounts::{Accounts, SignIn};
+    use crate::server::mentions::{parse_mentions, MAX_MENTIONS_PER_MESSAGE};
+    use crate::server::bans::{Ban, BanTarget, Bans};
+    use crate::server::server_profile::ServerMessages;
+    use crate::config::{ConfigError, Reloader, ServerConfig};
+    use crate::control;
+    use crate::server::client::outbound::{OutboundQueue, OutboundStats, OverflowPolicy};
+    use crate::server::filters::{Blocklist, Caught, ContentFilter, ContentFilters, FilterAction, FilterChain, Filtered, Links};
+    use crate::server::connections::{ConnectionLimits, PendingHandshakes};
+    use crate::server::client::rate_limit::{Rate, RateLimiter, RateLimits, RateLimitStats, Verdict};
     use crate::client_api::ClientApi;
-    use std::collections::HashMap;
-    use crate::commands::Commands;
+    use std::collections::{HashMap, VecDeque};
+    use crate::commands::{Commands, split_commands};
     use std::{thread, time};
     use std::time::Duration;
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use clap::App;
+    use std::io::{Read, Write};
+
+    fn transmit(stream: &mut TcpStream, data: &str) {
+        stream.write_all(data.as_bytes()).unwrap();
+        stream.flush().unwrap();
+    }
+
+    /// Read the next command, the server sends as much as the socket takes
+    /// so the rest of a read is kept for the next call on the same stream.
+    fn receive(stream: &mut TcpStream) -> Commands {
+        let key = stream_key(stream);
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(command) = received().lock().unwrap().get_mut(&key).and_then(|(commands, _)| commands.pop_front()) {
+                return Commands::from(command);
+            }
+            let size = match stream.read(&mut buffer) {
+                Ok(size) if size > 0 => size,
+                _ => return Commands::Error(None),
+            };
+            let mut received = received().lock().unwrap();
+            let (commands, partial) = received.entry(key.clone()).or_default();
+            partial.push_str(&String::from_utf8_lossy(&buffer[..size]));
+            let data = std::mem::take(partial);
+            let mut split = split_commands(&data);
+            // the last command may carry on in the next read if this one filled the buffer.
+            if size == buffer.len() {
+                *partial = split.pop().unwrap_or("").to_string();
+            }
+            commands.extend(split.into_iter().map(String::from));
+        }
+    }
+
+    /// Commands read but not yet received, and the start of an unfinished one.
+    type Received = (VecDeque<String>, String);
+
+    /// What has been read from each stream.
+    fn received() -> &'static Mutex<HashMap<String, Received>> {
+        static RECEIVED: OnceLock<Mutex<HashMap<String, Received>>> = OnceLock::new();
+        RECEIVED.get_or_init(Default::default)
+    }
+
+    fn stream_key(stream: &TcpStream) -> String {
+        format!("{:?}-{:?}", stream.local_addr().ok(), stream.peer_addr().ok())
+    }
+
+    /// A server keeping everything it stores in a directory of its own, so
+    /// tests don't leave anything in ./data or trip over an earlier run.
+    fn test_server(address: &str) -> Server {
+        let port = address.rsplit(':').next().unwrap();
+        let dir = std::env::temp_dir().join(format!("rust-chat-test-{}-{}", std::process::id(), port));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let mut server = Server::new("Server-01", address, "noreply@email.com");
+        server.set_accounts_path(&path("accounts"));
+        server.set_inbox(Inbox::new(&path("inbox"), DEFAULT_INBOX_QUOTA, DEFAULT_INBOX_EXPIRY));
+        server.set_history_path(&path("history.log"));
+        server.set_bans_path(&path("bans"));
+        server.set_transfer_limits(&path("transfers"), DEFAULT_MAX_FILE_SIZE, DEFAULT_TRANSFER_QUOTA);
+        server
+    }
+
+    /// The secrets servers have handed out, by server address and uuid.
+    fn secrets() -> &'static Mutex<HashMap<String, String>> {
+        static SECRETS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+        SECRETS.get_or_init(Default::default)
+    }
+
+    fn connect_client(address: &str, uuid: &str, name: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        received().lock().unwrap().remove(&stream_key(&stream));
+
+        assert_eq!(receive(&mut stream), Commands::Request(None));
+        let key = format!("{}/{}", address, uuid);
+        match secrets().lock().unwrap().get(&key) {
+            Some(secret) => transmit(&mut stream, format!("!connect: uuid:{} name:{} host:127.0.0.1 secret:{}", uuid, name, secret).as_str()),
+            None => transmit(&mut stream, format!("!connect: uuid:{} name:{} host:127.0.0.1", uuid, name).as_str()),
+        }
+        match receive(&mut stream) {
+            Commands::Success(Some(params)) => { secrets().lock().unwrap().insert(key, params.get("secret").unwrap().clone()); },
+            Commands::Success(None) => {},
+            command => panic!("expected to be connected, got {:?}", command),
+        }
+        thread::sleep(Duration::from_millis(500));
+        stream
+    }
+
+    /// Connect a client and leave straight away, so it has an account that
+    /// messages can be queued for while it is offline.
+    fn register_client(address: &str, uuid: &str, name: &str) {
+        let mut stream = connect_client(address, uuid, name);
+        transmit(&mut stream, "!disconnect:");
+        thread::sleep(Duration::from_millis(300));
+    }
+
+    /// Read the announcement of another client connecting and acknowledge it.
+    fn expect_client(stream: &mut TcpStream, uuid: &str) {
+        match receive(stream) {
+            Commands::Client(Some(params)) => {
+                assert_eq!(params.get("uuid").unwrap(), uuid);
+                transmit(stream, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+            },
+            command => panic!("expected {} to be announced, got {:?}", uuid, command),
+        }
+    }
+
+    #[test]
+    fn test_command_escaping() {
+        let content = String::from(r#"she said "hi!" \ then "left""#);
+        let message = Commands::Message(Some([(String::from("to"), String::from("a")), (String::from("content"), content.clone())].iter().cloned().collect()));
+        let data = message.to_string();
+        assert_eq!(Commands::from(data.clone()), message);
+
+        // a quote inside a value doesn't end it, so the `!` in it doesn't start a command.
+        let stream = data.clone() + &Commands::HeartBeat(None).to_string();
+        let commands = split_commands(&stream);
+        assert_eq!(commands, vec![data.as_str(), "!heartbeat:"]);
+        match Commands::from(commands[0].to_string()) {
+            Commands::Message(Some(params)) => assert_eq!(params.get("content").unwrap(), &content),
+            command => panic!("expected a message, got {:?}", command),
+        }
+
+        // the stores keep a command per line, so a line break can't start another one.
+        let content = String::from("x\n!delete: id:1\r\n\\n");
+        let message = Commands::Message(Some([(String::from("content"), content.clone())].iter().cloned().collect()));
+        let data = message.to_string();
+        assert!(!data.contains('\n') && !data.contains('\r'));
+        assert_eq!(Commands::from(data), message);
+    }
 
     #[test]
     fn test_server_info() {
@@ -127,16 +603,16 @@ mod tests {
         let address = "0.0.0.0:6000";
         let owner = "noreply@email.com";
 
-        let server = Server::new(name, address, owner);
+        let server = test_server(address);
         let result = server.start();
 
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
 
         let dur = time::Duration::from_millis(1000);
         thread::sleep(dur);
         
         let api = ClientApi::get_info("127.0.0.1:6000");
-        assert_eq!(api.is_ok(), true);
+        assert!(api.is_ok());
         if let Ok(api) = api {
             println!("received: {:?}", api);
             let mut map = HashMap::new();
@@ -151,28 +627,1375 @@ mod tests {
 
     #[test]
     fn test_server_connect() {
-        let name = "Server-01";
         let address = "0.0.0.0:6001";
-        let owner = "noreply@email.com";
 
-        let server = Server::new(name, address, owner);
-        let _ = server.start().unwrap();
+        let server = test_server(address);
+        server.start().unwrap();
 
         let api_result = ClientApi::new(address);
-        assert_eq!(api_result.is_ok(), true);
-        if let Ok(api) = api_result {
-            std::thread::sleep(std::time::Duration::from_secs(2));
+        assert!(api_result.is_ok());
+        std::thread::sleep(std::time::Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_client_announcements() {
+        let server = test_server("0.0.0.0:6009");
+        server.start().unwrap();
+
+        // a connect missing its details is refused without taking the server down.
+        let mut stranger = TcpStream::connect("127.0.0.1:6009").unwrap();
+        stranger.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&mut stranger), Commands::Request(None));
+        transmit(&mut stranger, "!connect: name:stranger");
+        match receive(&mut stranger) {
+            Commands::Error(Some(params)) => assert_eq!(params.get("reason").unwrap(), "connect needs a uuid, name and host"),
+            command => panic!("expected the connect to be refused, got {:?}", command),
+        }
+
+        // names are checked the same way as in a profile update.
+        let mut stranger = TcpStream::connect("127.0.0.1:6009").unwrap();
+        stranger.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&mut stranger), Commands::Request(None));
+        transmit(&mut stranger, "!connect: uuid:announce-0003 name:\"bad name!\" host:127.0.0.1");
+        match receive(&mut stranger) {
+            Commands::Error(Some(params)) => assert_eq!(params.get("reason").unwrap(), "name may only contain letters, numbers, '_', '-' and '.'"),
+            command => panic!("expected the connect to be refused, got {:?}", command),
+        }
+
+        let mut alice = connect_client("127.0.0.1:6009", "announce-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6009", "announce-0002", "bob");
+        expect_client(&mut alice, "announce-0002");
+
+        transmit(&mut bob, "!disconnect:");
+        match receive(&mut alice) {
+            Commands::ClientRemove(Some(params)) => assert_eq!(params.get("uuid").unwrap(), "announce-0002"),
+            command => panic!("expected bob to be removed, got {:?}", command),
+        }
+        transmit(&mut alice, "!success:");
+
+        // the server is still responsive after handling the disconnect.
+        transmit(&mut alice, "!heartbeat:");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+    }
+
+    #[test]
+    fn test_offline_inbox() {
+        let inbox_path = std::env::temp_dir().join("rust-chat-server-test-inbox");
+        let _ = std::fs::remove_dir_all(&inbox_path);
+
+        let mut server = test_server("0.0.0.0:6002");
+        server.set_inbox(Inbox::new(inbox_path.to_str().unwrap(), 10, Duration::from_secs(60)));
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6002", "inbox-0001", "alice");
+
+        // only clients that have connected before get an inbox.
+        transmit(&mut alice, "!message: to:inbox-9999 content:hello");
+        assert_eq!(receive(&mut alice), Commands::Error(Some([(String::from("reason"), String::from("unknown recipient"))].iter().cloned().collect())));
+        assert!(!inbox_path.exists());
+
+        let mut bob = connect_client("127.0.0.1:6002", "inbox-0002", "bob");
+        expect_client(&mut alice, "inbox-0002");
+        transmit(&mut bob, "!disconnect:");
+        match receive(&mut alice) {
+            Commands::ClientRemove(Some(params)) => transmit(&mut alice, format!("!success: ack:{}", params.get("ack").unwrap()).as_str()),
+            command => panic!("expected bob to be removed, got {:?}", command),
+        }
+
+        transmit(&mut alice, "!message: to:inbox-0002 content:\"hello: bob\"");
+        match receive(&mut alice) {
+            Commands::Success(Some(params)) => assert!(params.contains_key("id")),
+            command => panic!("expected success, got {:?}", command),
+        }
+
+        let mut bob = connect_client("127.0.0.1:6002", "inbox-0002", "bob");
+        match receive(&mut bob) {
+            Commands::Message(Some(params)) => {
+                assert_eq!(params.get("from").unwrap(), "inbox-0001");
+                assert_eq!(params.get("content").unwrap(), "hello: bob");
+            },
+            command => panic!("expected queued message, got {:?}", command),
+        }
+
+        // the message stays queued until bob acknowledges it.
+        transmit(&mut bob, "!disconnect:");
+        thread::sleep(Duration::from_millis(1500));
+        let mut bob = connect_client("127.0.0.1:6002", "inbox-0002", "bob");
+        let ack = match receive(&mut bob) {
+            Commands::Message(Some(params)) => params.get("ack").unwrap().clone(),
+            command => panic!("expected queued message again, got {:?}", command),
+        };
+        transmit(&mut bob, format!("!success: ack:{}", ack).as_str());
+
+        let inbox = Inbox::new(inbox_path.to_str().unwrap(), 10, Duration::from_secs(60));
+        for _ in 0..10 {
+            if inbox.pending("inbox-0002").unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        assert!(inbox.pending("inbox-0002").unwrap().is_empty());
+
+        // uuids that only differ in characters that can't go in a file name keep apart.
+        inbox.store("inbox/0003", &Commands::HeartBeat(None)).unwrap();
+        assert!(inbox.pending("inbox_0003").unwrap().is_empty());
+        assert!(matches!(inbox.pending("inbox/0003").unwrap().as_slice(), [Commands::HeartBeat(None)]));
+        let _ = std::fs::remove_dir_all(&inbox_path);
+    }
+
+    #[test]
+    fn test_room_typing() {
+        let server = test_server("0.0.0.0:6004");
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6004", "typing-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6004", "typing-0002", "bob");
+        expect_client(&mut alice, "typing-0002");
+
+        transmit(&mut alice, "!join: room:general");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        transmit(&mut bob, "!join: room:general");
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        match receive(&mut alice) {
+            Commands::Join(Some(params)) => assert_eq!(params.get("uuid").unwrap(), "typing-0002"),
+            command => panic!("expected bob to join the room, got {:?}", command),
+        }
+
+        transmit(&mut alice, "!typing: room:general");
+        match receive(&mut bob) {
+            Commands::Typing(Some(params)) => {
+                assert_eq!(params.get("from").unwrap(), "typing-0001");
+                assert_eq!(params.get("state").unwrap(), "start");
+            },
+            command => panic!("expected typing indicator, got {:?}", command),
+        }
+
+        // the indicator expires on its own when it isn't refreshed.
+        bob.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        match receive(&mut bob) {
+            Commands::Typing(Some(params)) => assert_eq!(params.get("state").unwrap(), "stop"),
+            command => panic!("expected typing to stop, got {:?}", command),
+        }
+
+        transmit(&mut alice, "!message: room:general content:\"hi all\"");
+        match receive(&mut bob) {
+            Commands::Message(Some(params)) => assert_eq!(params.get("room").unwrap(), "general"),
+            command => panic!("expected room message, got {:?}", command),
+        }
+        transmit(&mut bob, "!success:");
+
+        // a client that goes while typing doesn't leave its indicator behind.
+        transmit(&mut alice, "!typing: room:general");
+        assert!(matches!(receive(&mut bob), Commands::Typing(Some(_))));
+        transmit(&mut alice, "!disconnect:");
+        match receive(&mut bob) {
+            Commands::Typing(Some(params)) => {
+                assert_eq!(params.get("from").unwrap(), "typing-0001");
+                assert_eq!(params.get("state").unwrap(), "stop");
+            },
+            command => panic!("expected typing to stop, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_message_edit_and_delete() {
+        let history_path = std::env::temp_dir().join("rust-chat-server-test-history.log");
+        let _ = std::fs::remove_file(&history_path);
+        let accounts = std::env::temp_dir().join(format!("rust-chat-edit-accounts-{}", std::process::id()));
+
+        let mut server = test_server("0.0.0.0:6005");
+        server.set_history_path(history_path.to_str().unwrap());
+        server.set_accounts_path(accounts.to_str().unwrap());
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6005", "edit-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6005", "edit-0002", "bob");
+        expect_client(&mut alice, "edit-0002");
+
+        transmit(&mut alice, "!message: to:edit-0002 content:helo");
+        assert!(matches!(receive(&mut bob), Commands::Message(Some(_))));
+        transmit(&mut bob, "!success:");
+
+        let id = match receive(&mut alice) {
+            Commands::Success(Some(params)) => params.get("id").unwrap().clone(),
+            command => panic!("expected success, got {:?}", command),
+        };
+        // delivery receipt
+        assert!(matches!(receive(&mut alice), Commands::Receipt(Some(_))));
+
+        transmit(&mut bob, format!("!delete: id:{}", id).as_str());
+        assert!(matches!(receive(&mut bob), Commands::Error(Some(_))));
+
+        transmit(&mut alice, format!("!edit: id:{} content:hello", id).as_str());
+        match receive(&mut bob) {
+            Commands::Edit(Some(params)) => {
+                assert_eq!(params.get("id").unwrap(), &id);
+                assert_eq!(params.get("content").unwrap(), "hello");
+            },
+            command => panic!("expected edit, got {:?}", command),
+        }
+        transmit(&mut bob, "!success:");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+
+        // moderators can edit other people's messages, as they can delete them.
+        transmit(&mut bob, format!("!edit: id:{} content:removed", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("only the author can change a message"))].iter().cloned().collect())));
+        server.set_role("edit-0002", Role::Moderator);
+        for stream in [&mut alice, &mut bob] {
+            match receive(stream) {
+                Commands::Client(Some(params)) => transmit(stream, format!("!success: ack:{}", params.get("ack").unwrap()).as_str()),
+                command => panic!("expected role update, got {:?}", command),
+            }
+        }
+        transmit(&mut bob, format!("!edit: id:{} content:removed", id).as_str());
+        match receive(&mut alice) {
+            Commands::Edit(Some(params)) => assert_eq!(params.get("content").unwrap(), "removed"),
+            command => panic!("expected edit, got {:?}", command),
+        }
+        transmit(&mut alice, "!success:");
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        let _ = std::fs::remove_file(&accounts);
+    }
+
+    #[test]
+    fn test_history_search() {
+        let history_path = std::env::temp_dir().join("rust-chat-server-test-search.log");
+        let inbox_path = std::env::temp_dir().join("rust-chat-server-test-search-inbox");
+        let _ = std::fs::remove_file(&history_path);
+        let _ = std::fs::remove_dir_all(&inbox_path);
+
+        let mut server = test_server("0.0.0.0:6006");
+        server.set_history_path(history_path.to_str().unwrap());
+        server.set_inbox(Inbox::new(inbox_path.to_str().unwrap(), 10, Duration::from_secs(60)));
+        server.start().unwrap();
+
+        register_client("127.0.0.1:6006", "search-0002", "bob");
+        let mut alice = connect_client("127.0.0.1:6006", "search-0001", "alice");
+
+        // bob is offline so the messages are queued rather than waiting on acks.
+        transmit(&mut alice, "!message: to:search-0002 content:\"the quarterly report is late\"");
+        assert!(matches!(receive(&mut alice), Commands::Success(Some(_))));
+        transmit(&mut alice, "!message: to:search-0002 content:\"lunch at noon?\"");
+        assert!(matches!(receive(&mut alice), Commands::Success(Some(_))));
+
+        transmit(&mut alice, "!search: q:\"Quarterly REPORT\"");
+        match receive(&mut alice) {
+            Commands::History(Some(params)) => assert_eq!(params.get("content").unwrap(), "the quarterly report is late"),
+            command => panic!("expected search result, got {:?}", command),
+        }
+        match receive(&mut alice) {
+            Commands::Success(Some(params)) => assert_eq!(params.get("total").unwrap(), "1"),
+            command => panic!("expected end of results, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_file_transfer() {
+        let transfer_path = std::env::temp_dir().join("rust-chat-server-test-transfers");
+
+        let mut server = test_server("0.0.0.0:6007");
+        server.set_transfer_limits(transfer_path.to_str().unwrap(), 1024, 4096);
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6007", "transfer-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6007", "transfer-0002", "bob");
+        expect_client(&mut alice, "transfer-0002");
+
+        // sha256 of "hello"
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        transmit(&mut alice, format!("!fileOffer: to:transfer-0002 name:hello.txt size:5 hash:{}", hash).as_str());
+        let id = match receive(&mut alice) {
+            Commands::Success(Some(params)) => params.get("transfer").unwrap().clone(),
+            command => panic!("expected transfer id, got {:?}", command),
+        };
+
+        transmit(&mut alice, format!("!fileChunk: transfer:{} seq:0 data:68656c6c6f", id).as_str());
+        match receive(&mut bob) {
+            Commands::FileOffer(Some(params)) => {
+                assert_eq!(params.get("name").unwrap(), "hello.txt");
+                assert_eq!(params.get("from").unwrap(), "transfer-0001");
+            },
+            command => panic!("expected file offer, got {:?}", command),
+        }
+        transmit(&mut bob, "!success:");
+
+        match receive(&mut alice) {
+            Commands::Success(Some(params)) => assert_eq!(params.get("complete").unwrap(), "true"),
+            command => panic!("expected upload complete, got {:?}", command),
+        }
+
+        // asking for more chunks than there are stops at the last one, and a
+        // chunk can be asked for again until bob confirms the download.
+        for _ in 0..2 {
+            transmit(&mut bob, format!("!fileGet: transfer:{} seq:0 count:4", id).as_str());
+            match receive(&mut bob) {
+                Commands::FileChunk(Some(params)) => assert_eq!(params.get("data").unwrap(), "68656c6c6f"),
+                command => panic!("expected file chunk, got {:?}", command),
+            }
+        }
+        transmit(&mut bob, format!("!fileGet: transfer:{} seq:1", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("chunk out of range"))].iter().cloned().collect())));
+
+        // the file is gone once bob has confirmed the download.
+        transmit(&mut bob, format!("!fileGet: transfer:{} done:true", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        transmit(&mut bob, format!("!fileGet: transfer:{} seq:0", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("unknown transfer"))].iter().cloned().collect())));
+    }
+
+    #[test]
+    fn test_transfer_restart() {
+        let path = std::env::temp_dir().join("rust-chat-server-test-transfer-restart");
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("1.part"), b"left behind").unwrap();
+
+        let contents: Vec<u8> = (0..CHUNK_SIZE + 100).map(|i| i as u8).collect();
+        let params: HashMap<String, String> = [
+            (String::from("to"), String::from("b")),
+            (String::from("name"), String::from("data.bin")),
+            (String::from("size"), contents.len().to_string()),
+            (String::from("hash"), hex_encode(&sha256(&contents))),
+        ].iter().cloned().collect();
+
+        let mut transfers = Transfers::open(path.to_str().unwrap(), 1024, 4096).unwrap();
+        let id = transfers.offer("a", &params).unwrap().id.clone();
+        transfers.upload("a", &id, 0, &hex_encode(&contents[..CHUNK_SIZE])).unwrap();
+        // half a chunk written when the server went down.
+        let mut part = std::fs::OpenOptions::new().append(true).open(path.join(format!("{}.part", id))).unwrap();
+        part.write_all(&contents[CHUNK_SIZE..CHUNK_SIZE + 50]).unwrap();
+        drop(transfers);
+
+        let mut transfers = Transfers::open(path.to_str().unwrap(), 1024, 4096).unwrap();
+        assert!(!path.join("1.part").exists());
+        assert_eq!(transfers.get(&id).unwrap().received, CHUNK_SIZE as u64);
+        assert!(transfers.upload("a", &id, 1, &hex_encode(&contents[CHUNK_SIZE..])).unwrap().is_complete());
+
+        assert_eq!(transfers.download("b", &id, 0).unwrap(), hex_encode(&contents[..CHUNK_SIZE]));
+        assert_eq!(transfers.download("b", &id, 1).unwrap(), hex_encode(&contents[CHUNK_SIZE..]));
+        assert!(transfers.get(&id).is_some());
+        assert_eq!(transfers.finish("a", &id), Err("unknown transfer"));
+        assert_eq!(transfers.finish("b", &id), Ok(()));
+        assert!(transfers.get(&id).is_none());
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_message_retransmit() {
+        let inbox_path = std::env::temp_dir().join(format!("rust-chat-retransmit-inbox-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&inbox_path);
+
+        let mut server = test_server("0.0.0.0:6008");
+        server.set_inbox(Inbox::new(inbox_path.to_str().unwrap(), 10, Duration::from_secs(60)));
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6008", "retransmit-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6008", "retransmit-0002", "bob");
+        expect_client(&mut alice, "retransmit-0002");
+
+        transmit(&mut alice, "!message: to:retransmit-0002 content:hello");
+        let ack = match receive(&mut bob) {
+            Commands::Message(Some(params)) => params.get("ack").unwrap().clone(),
+            command => panic!("expected message, got {:?}", command),
+        };
+        assert!(matches!(receive(&mut alice), Commands::Success(Some(_))));
+
+        // bob doesn't acknowledge, so the message is sent again with the same number.
+        match receive(&mut bob) {
+            Commands::Message(Some(params)) => assert_eq!(params.get("ack").unwrap(), &ack),
+            command => panic!("expected retransmitted message, got {:?}", command),
+        }
+        transmit(&mut bob, format!("!success: ack:{}", ack).as_str());
+
+        match receive(&mut alice) {
+            Commands::Receipt(Some(params)) => assert_eq!(params.get("status").unwrap(), "delivered"),
+            command => panic!("expected delivery receipt, got {:?}", command),
+        }
+
+        // a message bob never acknowledges is kept in his inbox.
+        transmit(&mut alice, "!message: to:retransmit-0002 content:again");
+        assert!(matches!(receive(&mut alice), Commands::Success(Some(_))));
+
+        // the attempts run out on time, so the inbox is checked until they do.
+        let inbox = Inbox::new(inbox_path.to_str().unwrap(), 10, Duration::from_secs(60));
+        let started = time::Instant::now();
+        while inbox.pending("retransmit-0002").unwrap().is_empty() && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        match inbox.pending("retransmit-0002").unwrap().as_slice() {
+            [Commands::Message(Some(params))] => assert_eq!(params.get("content").unwrap(), "again"),
+            pending => panic!("expected the message in the inbox, got {:?}", pending),
+        }
+        let _ = std::fs::remove_dir_all(&inbox_path);
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = ServerConfig::parse("test.toml", "name = \"Test\"\n[limits]\nqueue_capacity = 8\n").unwrap();
+        assert_eq!(config.name, "Test");
+        assert_eq!(config.limits.queue_capacity, 8);
+        assert_eq!(config.address, ServerConfig::default().address);
+        assert!(config.validate().is_ok());
+
+        // the default config round trips through its own output.
+        let default = ServerConfig::default();
+        assert_eq!(ServerConfig::parse("default.toml", &default.to_toml()).unwrap(), default);
+
+        assert!(matches!(ServerConfig::parse("test.toml", "nmae = \"Test\""), Err(ConfigError::Parse(_, _))));
+
+        let mut config = ServerConfig::default();
+        config.limits.transfer_quota = 1;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = ServerConfig::parse("test.toml", "[rate_limits]\nmessages = 0.0\n").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_settings_reload() {
+        let bans = std::env::temp_dir().join(format!("rust-chat-reload-bans-{}", std::process::id()));
+        let _ = std::fs::remove_file(&bans);
+
+        let mut server = test_server("0.0.0.0:6010");
+        server.set_bans_path(bans.to_str().unwrap());
+        server.start().unwrap();
+        let mut alice = connect_client("127.0.0.1:6010", "reload-0001", "alice");
+
+        // a ban added to the file while the server runs takes effect on reload.
+        Bans::open(bans.to_str().unwrap()).unwrap().add(Ban::new(BanTarget::Uuid(String::from("reload-0001")), "spam", None)).unwrap();
+
+        let mut settings = ServerConfig::default().live_settings();
+        settings.motd = String::from("welcome back");
+        server.apply_settings(settings);
+        match receive(&mut alice) {
+            Commands::Disconnect(Some(params)) => assert_eq!(params.get("reason").unwrap(), "banned: spam"),
+            command => panic!("expected disconnect, got {:?}", command),
+        }
+        assert_eq!(server.list_bans().unwrap().len(), 1);
+
+        match ClientApi::get_info("127.0.0.1:6010") {
+            Ok(Commands::Info(Some(params))) => assert_eq!(params.get("motd").unwrap(), "welcome back"),
+            result => panic!("expected server info, got {:?}", result),
+        }
+
+        let config = ServerConfig { address: String::from("0.0.0.0:7000"), motd: String::from("hello"), ..ServerConfig::default() };
+        assert_eq!(ServerConfig::default().restart_required(&config), vec!["address"]);
+
+        // a setting that needs a restart keeps its running value, so it is still
+        // reported the next time the file is read.
+        let path = std::env::temp_dir().join(format!("rust-chat-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "name = \"Renamed\"\nmotd = \"hello again\"\n").unwrap();
+        let reloader = Reloader::new(path.to_str(), App::new("test").get_matches_from(vec!["test"]), ServerConfig::default());
+        assert_eq!(reloader.reload(&server).unwrap(), vec!["name"]);
+        assert_eq!(reloader.reload(&server).unwrap(), vec!["name"]);
+        match ClientApi::get_info("127.0.0.1:6010") {
+            Ok(Commands::Info(Some(params))) => assert_eq!(params.get("motd").unwrap(), "hello again"),
+            result => panic!("expected server info, got {:?}", result),
+        }
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&bans);
+    }
+
+    #[test]
+    fn test_server_events() {
+        let server = test_server("0.0.0.0:6011");
+        let events = server.subscribe();
+        server.start().unwrap();
+
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ServerEvent::Started(address) => assert_eq!(address, "0.0.0.0:6011"),
+            event => panic!("expected started, got {:?}", event),
         }
+
+        let _alice = connect_client("127.0.0.1:6011", "events-0001", "alice");
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ServerEvent::Activity(line) => assert!(line.contains("events-0001")),
+            event => panic!("expected activity, got {:?}", event),
+        }
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ServerEvent::Clients(clients) => {
+                assert_eq!(clients.len(), 1);
+                assert_eq!(clients[0].uuid, "events-0001");
+                assert_eq!(clients[0].name, "alice");
+            },
+            event => panic!("expected client list, got {:?}", event),
+        }
+
+        server.stop();
+        assert!(events.iter().any(|event| matches!(event, ServerEvent::Stopped)));
+    }
+
+    #[test]
+    fn test_admin_actions() {
+        // the bans are kept apart so localhost isn't banned for the other tests.
+        let bans = std::env::temp_dir().join(format!("rust-chat-admin-bans-{}", std::process::id()));
+        let mut server = test_server("0.0.0.0:6012");
+        server.set_bans_path(bans.to_str().unwrap());
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6012", "admin-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6012", "admin-0002", "bob");
+        expect_client(&mut alice, "admin-0002");
+
+        server.announce("maintenance tonight");
+        for stream in [&mut alice, &mut bob] {
+            match receive(stream) {
+                Commands::Announce(Some(params)) => {
+                    assert_eq!(params.get("content").unwrap(), "maintenance tonight");
+                    transmit(stream, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+                },
+                command => panic!("expected announcement, got {:?}", command),
+            }
+        }
+
+        server.kick("admin-0002", "spamming");
+        match receive(&mut bob) {
+            Commands::Disconnect(Some(params)) => assert_eq!(params.get("reason").unwrap(), "spamming"),
+            command => panic!("expected disconnect, got {:?}", command),
+        }
+        match receive(&mut alice) {
+            Commands::ClientRemove(Some(params)) => {
+                assert_eq!(params.get("uuid").unwrap(), "admin-0002");
+                transmit(&mut alice, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+            },
+            command => panic!("expected client removal, got {:?}", command),
+        }
+
+        server.ban("admin-0001", "abuse", false);
+        match receive(&mut alice) {
+            Commands::Disconnect(Some(params)) => assert_eq!(params.get("reason").unwrap(), "banned: abuse"),
+            command => panic!("expected disconnect, got {:?}", command),
+        }
+        thread::sleep(Duration::from_millis(300));
+
+        // only the uuid is banned, so carol can still connect from the same address.
+        let mut carol = connect_client("127.0.0.1:6012", "admin-0003", "carol");
+
+        // when asked, the address is banned along with the uuid, so dave is refused before the handshake.
+        server.ban("admin-0003", "abuse", true);
+        match receive(&mut carol) {
+            Commands::Disconnect(Some(params)) => assert_eq!(params.get("reason").unwrap(), "banned: abuse"),
+            command => panic!("expected disconnect, got {:?}", command),
+        }
+        thread::sleep(Duration::from_millis(300));
+
+        let mut dave = TcpStream::connect("127.0.0.1:6012").unwrap();
+        dave.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        match receive(&mut dave) {
+            Commands::Error(Some(params)) => assert_eq!(params.get("reason").unwrap(), "banned"),
+            command => panic!("expected to be refused, got {:?}", command),
+        }
+        let _ = std::fs::remove_file(&bans);
+    }
+
+    #[test]
+    fn test_remote_admin() {
+        assert!(token_matches("secret", Some("secret")));
+        assert!(!token_matches("secret", Some("secre")));
+        assert!(!token_matches("secret", None));
+        // an empty token turns remote administration off rather than accepting anything.
+        assert!(!token_matches("", Some("")));
+
+        let mut server = test_server("0.0.0.0:6013");
+        server.set_admin_token("secret");
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6013", "remote-0001", "alice");
+        let refused = Commands::Error(Some([(String::from("reason"), String::from("not authorised"))].iter().cloned().collect()));
+        assert_eq!(ClientApi::get_clients("127.0.0.1:6013", "wrong").unwrap(), (refused, Vec::new()));
+        let (reply, clients) = ClientApi::get_clients("127.0.0.1:6013", "secret").unwrap();
+        assert_eq!(reply, Commands::Success(None));
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].get("uuid").unwrap(), "remote-0001");
+
+        let announce: HashMap<String, String> = [(String::from("action"), String::from("announce")), (String::from("content"), String::from("hello"))].iter().cloned().collect();
+        match ClientApi::admin_list("127.0.0.1:6013", "wrong", announce.clone()).unwrap().0 {
+            Commands::Error(Some(params)) => assert_eq!(params.get("reason").unwrap(), "not authorised"),
+            command => panic!("expected to be refused, got {:?}", command),
+        }
+        assert_eq!(ClientApi::admin_list("127.0.0.1:6013", "secret", announce).unwrap().0, Commands::Success(None));
+        match receive(&mut alice) {
+            Commands::Announce(Some(params)) => assert_eq!(params.get("content").unwrap(), "hello"),
+            command => panic!("expected announcement, got {:?}", command),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_control_socket() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+        let server = Arc::new(test_server("0.0.0.0:6014"));
+        server.start().unwrap();
+        let reloader = Arc::new(Reloader::new(None, App::new("test").get_matches_from(vec!["test"]), ServerConfig::default()));
+        let path = std::env::temp_dir().join(format!("rust-chat-control-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        control::listen(path.to_str().unwrap(), server.clone(), reloader.clone()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // a second server doesn't take the socket away from the one using it.
+        assert_eq!(control::listen(path.to_str().unwrap(), server, reloader).unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+
+        let _alice = connect_client("127.0.0.1:6014", "control-0001", "alice");
+
+        // replies are one command per line, ending with a success or error.
+        fn request(stream: &mut UnixStream, reader: &mut BufReader<UnixStream>, line: &str) -> Vec<Commands> {
+            stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+            let mut reply = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let command = Commands::from(line.trim().to_string());
+                let done = matches!(command, Commands::Success(_) | Commands::Error(_));
+                reply.push(command);
+                if done {
+                    return reply;
+                }
+            }
+        }
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        match request(&mut stream, &mut reader, "!admin: action:list").as_slice() {
+            [Commands::Client(Some(params)), Commands::Success(None)] => assert_eq!(params.get("uuid").unwrap(), "control-0001"),
+            reply => panic!("expected the client list, got {:?}", reply),
+        }
+        match request(&mut stream, &mut reader, "!admin: action:stats").as_slice() {
+            [Commands::Success(Some(params))] => assert_eq!(params.get("clients").unwrap(), "1"),
+            reply => panic!("expected stats, got {:?}", reply),
+        }
+        match request(&mut stream, &mut reader, "!admin: action:reload").as_slice() {
+            [Commands::Error(Some(params))] => assert!(params.get("reason").unwrap().contains("config file")),
+            reply => panic!("expected reload to fail, got {:?}", reply),
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_account_secrets() {
+        let server = test_server("0.0.0.0:6020");
+        server.start().unwrap();
+
+        let refusal = |connect: &str| {
+            let mut stream = TcpStream::connect("127.0.0.1:6020").unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(receive(&mut stream), Commands::Request(None));
+            transmit(&mut stream, connect);
+            match receive(&mut stream) {
+                Commands::Error(Some(params)) => params.get("reason").cloned().unwrap(),
+                command => panic!("expected to be refused, got {:?}", command),
+            }
+        };
+
+        let mut alice = connect_client("127.0.0.1:6020", "secret-0001", "alice");
+        assert_eq!(refusal("!connect: uuid:secret-0001 name:mallory host:127.0.0.1"), "already connected");
+
+        transmit(&mut alice, "!disconnect:");
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(refusal("!connect: uuid:secret-0001 name:mallory host:127.0.0.1"), "not authorised");
+        assert_eq!(refusal("!connect: uuid:secret-0001 name:mallory host:127.0.0.1 secret:guess"), "not authorised");
+
+        let mut alice = connect_client("127.0.0.1:6020", "secret-0001", "alice");
+        transmit(&mut alice, "!heartbeat:");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+
+        // an account saved before it had a secret is given one, but not its old role.
+        let path = std::env::temp_dir().join(format!("rust-chat-secrets-{}", std::process::id()));
+        std::fs::write(&path, "!client: uuid:secret-0002 name:bob role:owner\n").unwrap();
+        let mut accounts = Accounts::open(path.to_str().unwrap()).unwrap();
+        let secret = match accounts.sign_in("secret-0002", "bob", None).unwrap() {
+            SignIn::Issued(secret) => secret,
+            signed_in => panic!("expected a new secret, got {:?}", signed_in),
+        };
+        assert_eq!(accounts.role("secret-0002"), Role::User);
+
+        let mut accounts = Accounts::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(accounts.sign_in("secret-0002", "bob", None).unwrap(), SignIn::Refused);
+        assert_eq!(accounts.sign_in("secret-0002", "bob", Some(&secret)).unwrap(), SignIn::Verified);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_roles() {
+        let accounts = std::env::temp_dir().join(format!("rust-chat-roles-{}", std::process::id()));
+        let mut server = test_server("0.0.0.0:6015");
+        server.set_accounts_path(accounts.to_str().unwrap());
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6015", "roles-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6015", "roles-0002", "bob");
+        expect_client(&mut alice, "roles-0002");
+
+        transmit(&mut bob, "!admin: action:kick uuid:roles-0001");
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("not allowed"))].iter().cloned().collect())));
+
+        server.set_role("roles-0001", Role::Moderator);
+        for stream in [&mut alice, &mut bob] {
+            match receive(stream) {
+                Commands::Client(Some(params)) => {
+                    assert_eq!(params.get("role").unwrap(), "moderator");
+                    transmit(stream, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+                },
+                command => panic!("expected role update, got {:?}", command),
+            }
+        }
+
+        transmit(&mut alice, "!admin: action:ban uuid:roles-0002");
+        assert_eq!(receive(&mut alice), Commands::Error(Some([(String::from("reason"), String::from("not allowed"))].iter().cloned().collect())));
+
+        // an address ban could catch anyone, so it takes the owner.
+        server.set_role("roles-0001", Role::Admin);
+        for stream in [&mut alice, &mut bob] {
+            match receive(stream) {
+                Commands::Client(Some(params)) => transmit(stream, format!("!success: ack:{}", params.get("ack").unwrap()).as_str()),
+                command => panic!("expected role update, got {:?}", command),
+            }
+        }
+        transmit(&mut alice, "!admin: action:ban address:0.0.0.0/0");
+        assert_eq!(receive(&mut alice), Commands::Error(Some([(String::from("reason"), String::from("not allowed"))].iter().cloned().collect())));
+        transmit(&mut alice, "!admin: action:ban uuid:roles-0002 linkAddress:true");
+        assert_eq!(receive(&mut alice), Commands::Error(Some([(String::from("reason"), String::from("not allowed"))].iter().cloned().collect())));
+
+        transmit(&mut alice, "!admin: action:kick uuid:roles-0002 reason:rude");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        match receive(&mut bob) {
+            Commands::Disconnect(Some(params)) => assert_eq!(params.get("reason").unwrap(), "rude"),
+            command => panic!("expected to be kicked, got {:?}", command),
+        }
+        let _ = std::fs::remove_file(&accounts);
+    }
+
+    #[test]
+    fn test_moderation() {
+        let bans = std::env::temp_dir().join(format!("rust-chat-moderation-bans-{}", std::process::id()));
+        let mut server = test_server("0.0.0.0:6016");
+        server.set_bans_path(bans.to_str().unwrap());
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6016", "moderation-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6016", "moderation-0002", "bob");
+        expect_client(&mut alice, "moderation-0002");
+
+        transmit(&mut alice, "!join: room:lobby");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        transmit(&mut bob, "!join: room:lobby");
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        assert!(matches!(receive(&mut alice), Commands::Join(Some(_))));
+
+        server.send(ServerMessages::Mute(String::from("lobby"), String::from("moderation-0002"), None));
+        thread::sleep(Duration::from_millis(300));
+        transmit(&mut bob, "!message: room:lobby content:hello");
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("muted in this room"))].iter().cloned().collect())));
+
+        server.send(ServerMessages::Unmute(String::from("lobby"), String::from("moderation-0002")));
+        thread::sleep(Duration::from_millis(300));
+        transmit(&mut bob, "!message: room:lobby content:hello");
+        assert!(matches!(receive(&mut bob), Commands::Success(Some(_))));
+
+        server.send(ServerMessages::Ban(Ban::new(BanTarget::Username(String::from("mallory")), "spam", Some(60)), false));
+        thread::sleep(Duration::from_millis(300));
+        let mut mallory = TcpStream::connect("127.0.0.1:6016").unwrap();
+        mallory.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&mut mallory), Commands::Request(None));
+        transmit(&mut mallory, "!connect: uuid:moderation-0003 name:mallory host:127.0.0.1");
+        match receive(&mut mallory) {
+            Commands::Error(Some(params)) => {
+                assert_eq!(params.get("reason").unwrap(), "banned");
+                assert!(params.contains_key("until"));
+            },
+            command => panic!("expected to be refused, got {:?}", command),
+        }
+
+        assert_eq!(server.list_bans().unwrap().len(), 1);
+        server.send(ServerMessages::Unban(BanTarget::Username(String::from("mallory"))));
+        assert!(server.list_bans().unwrap().is_empty());
+        let _ = std::fs::remove_file(&bans);
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let network: HashMap<String, String> = [(String::from("address"), String::from("192.168.1.0/24"))].iter().cloned().collect();
+        let network = BanTarget::from_params(&network).unwrap();
+        assert!(network.matches(None, None, "192.168.1.77".parse().unwrap()));
+        assert!(network.matches(None, None, "::ffff:192.168.1.5".parse().unwrap()));
+        assert!(!network.matches(None, None, "192.168.2.1".parse().unwrap()));
+
+        let path = std::env::temp_dir().join(format!("rust-chat-bans-{}", std::process::id()));
+        let mut bans = Bans::open(path.to_str().unwrap()).unwrap();
+        bans.add(Ban::new(network, "flooding", None)).unwrap();
+        bans.add(Ban::new(BanTarget::Uuid(String::from("ban-0001")), "spam", Some(0))).unwrap();
+        assert!(bans.find(Some("ban-0001"), None, "10.0.0.1".parse().unwrap()).is_none());
+        bans.expire().unwrap();
+
+        let mut bans = Bans::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(bans.list().len(), 1);
+        assert_eq!(bans.find(None, None, "192.168.1.1".parse().unwrap()).unwrap().reason, "flooding");
+
+        // an address banned along with a uuid is lifted with it, one banned on its own stays.
+        let ban = Ban::new(BanTarget::Uuid(String::from("ban-0002")), "spam", None);
+        bans.add(ban.linked_address("10.0.0.2".parse().unwrap()).unwrap()).unwrap();
+        bans.add(ban).unwrap();
+        let mut bans = Bans::open(path.to_str().unwrap()).unwrap();
+        assert!(bans.find(None, None, "10.0.0.2".parse().unwrap()).is_some());
+        assert!(bans.remove(&BanTarget::Uuid(String::from("ban-0002"))).unwrap());
+        assert!(bans.find(None, None, "10.0.0.2".parse().unwrap()).is_none());
+        assert_eq!(bans.list().len(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_outbound_overflow() {
+        fn presence(uuid: &str, state: &str) -> Commands {
+            Commands::Presence(Some([(String::from("uuid"), uuid.to_string()), (String::from("presence"), state.to_string())].iter().cloned().collect()))
+        }
+        let stats = std::sync::Arc::new(OutboundStats::default());
+
+        let queue = OutboundQueue::new(2, OverflowPolicy::Coalesce, stats.clone());
+        queue.send(presence("a", "away")).unwrap();
+        queue.send(Commands::Success(None)).unwrap();
+        queue.send(presence("a", "busy")).unwrap();
+        assert_eq!(stats.coalesced(), 1);
+        assert_eq!(queue.pop(), Some(presence("a", "busy")));
+
+        let queue = OutboundQueue::new(2, OverflowPolicy::DropOldest, stats.clone());
+        queue.send(presence("a", "away")).unwrap();
+        queue.send(Commands::Success(None)).unwrap();
+        queue.send(Commands::Error(None)).unwrap();
+        assert!(queue.send(Commands::Info(None)).is_err());
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.pop(), Some(Commands::Success(None)));
+
+        let queue = OutboundQueue::new(1, OverflowPolicy::Disconnect, stats.clone());
+        queue.send(Commands::Success(None)).unwrap();
+        assert!(queue.send(Commands::Success(None)).is_err());
+        assert!(queue.is_overflowed());
+        assert_eq!(stats.disconnected(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let message = Commands::Message(Some([(String::from("to"), String::from("a")), (String::from("content"), String::from("hi"))].iter().cloned().collect()));
+        let limits = RateLimits {
+            messages: Rate { rate: 1.0, burst: 2 },
+            mute_after: 3,
+            mute_duration: Duration::from_secs(10),
+            disconnect_after: 4,
+            ..RateLimits::default()
+        };
+        let stats = Arc::new(RateLimitStats::default());
+        let mut limiter = RateLimiter::new(limits, stats.clone());
+        let start = time::Instant::now();
+
+        assert_eq!(limiter.check_at(&message, start), Verdict::Allow);
+        assert_eq!(limiter.check_at(&message, start), Verdict::Allow);
+        assert!(matches!(limiter.check_at(&message, start), Verdict::Throttle(wait) if wait <= Duration::from_secs(1)));
+        // other kinds of command have their own limits.
+        assert_eq!(limiter.check_at(&Commands::Typing(None), start), Verdict::Allow);
+        assert_eq!(limiter.check_at(&Commands::Success(None), start), Verdict::Allow);
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(&message, later), Verdict::Allow);
+        assert!(matches!(limiter.check_at(&message, later), Verdict::Throttle(_)));
+        assert_eq!(limiter.check_at(&message, later), Verdict::Mute(Duration::from_secs(10)));
+        assert_eq!(limiter.check_at(&Commands::HeartBeat(None), later), Verdict::Drop);
+
+        let unmuted = later + Duration::from_secs(12);
+        assert_eq!(limiter.check_at(&message, unmuted), Verdict::Allow);
+        assert_eq!(limiter.check_at(&message, unmuted), Verdict::Allow);
+        assert_eq!(limiter.check_at(&message, unmuted), Verdict::Disconnect);
+        assert_eq!((stats.throttled(), stats.muted(), stats.disconnected()), (4, 1, 1));
+    }
+
+    #[test]
+    fn test_flood_protection() {
+        let mut server = test_server("0.0.0.0:6017");
+        server.set_rate_limits(RateLimits {
+            commands: Rate { rate: 0.25, burst: 2 },
+            mute_after: 2,
+            mute_duration: Duration::from_secs(1),
+            disconnect_after: 3,
+            ..RateLimits::default()
+        });
+        server.start().unwrap();
+
+        let mut stream = connect_client("127.0.0.1:6017", "flood-0001", "flooder");
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let heartbeat = |stream: &mut TcpStream| {
+            transmit(stream, "!heartbeat:");
+            receive(stream)
+        };
+        let reason = |command: Commands| match command {
+            Commands::Error(Some(params)) | Commands::Disconnect(Some(params)) => params.get("reason").cloned(),
+            _ => None,
+        };
+
+        assert_eq!(heartbeat(&mut stream), Commands::Success(None));
+        assert_eq!(heartbeat(&mut stream), Commands::Success(None));
+        assert_eq!(reason(heartbeat(&mut stream)).unwrap(), "rate limited");
+        assert_eq!(reason(heartbeat(&mut stream)).unwrap(), "muted for flooding");
+        // nothing is answered while muted.
+        assert_eq!(heartbeat(&mut stream), Commands::Error(None));
+        thread::sleep(Duration::from_millis(200));
+        assert!(matches!(heartbeat(&mut stream), Commands::Disconnect(Some(params)) if params.get("reason").unwrap() == "flooding"));
+
+        let stats = server.get_rate_limit_stats();
+        assert_eq!((stats.throttled(), stats.muted(), stats.disconnected()), (3, 1, 1));
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let mut server = test_server("0.0.0.0:6018");
+        server.set_connection_limits(ConnectionLimits { max_connections: 3, max_per_address: 2, max_pending: 8, max_pending_per_address: 2, handshake_timeout: Duration::from_secs(3) });
+        server.start().unwrap();
+
+        let connect = || {
+            let stream = TcpStream::connect("127.0.0.1:6018").unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream
+        };
+        let refusal = |mut stream: TcpStream| match receive(&mut stream) {
+            Commands::Error(Some(params)) => params.get("reason").cloned().unwrap(),
+            command => panic!("expected to be refused, got {:?}", command),
+        };
+
+        // a connector that never answers doesn't hold up anyone else.
+        let mut silent = connect();
+        assert_eq!(receive(&mut silent), Commands::Request(None));
+        let _alice = connect_client("127.0.0.1:6018", "limits-0001", "alice");
+        assert_eq!(refusal(connect()), "too many connections from your address");
+
+        assert_eq!(refusal(silent), "handshake timed out");
+        thread::sleep(Duration::from_millis(1500));
+        let _bob = connect_client("127.0.0.1:6018", "limits-0002", "bob");
+        assert_eq!(refusal(connect()), "too many connections from your address");
+        assert_eq!(server.get_stats().unwrap().get("refused").unwrap(), "2");
+
+        let limits = ConnectionLimits { max_connections: 8, max_per_address: 4, max_pending: 3, max_pending_per_address: 1, ..ConnectionLimits::default() };
+        let pending = PendingHandshakes::default();
+        let ip = "10.0.0.1".parse().unwrap();
+        let other = "10.0.0.2".parse().unwrap();
+        assert_eq!(limits.refusal(1, 1, &pending, ip), None);
+        assert_eq!(limits.refusal(8, 0, &pending, ip), Some("server full"));
+        // one address can only have a few handshakes waiting, however many connections it may have.
+        pending.start(ip);
+        assert_eq!(limits.refusal(0, 0, &pending, ip), Some("too many connections from your address"));
+        assert_eq!(limits.refusal(0, 0, &pending, other), None);
+        pending.start(other);
+        pending.start("10.0.0.3".parse().unwrap());
+        assert_eq!(limits.refusal(0, 0, &pending, "10.0.0.4".parse().unwrap()), Some("server busy, try again later"));
+        pending.finish(ip);
+        pending.finish(other);
+        pending.finish("10.0.0.3".parse().unwrap());
+        assert_eq!(pending.total(), 0);
+    }
+
+    /// A custom filter catching messages written in capitals.
+    #[derive(Debug)]
+    struct Shouting;
+
+    impl ContentFilter for Shouting {
+        fn check(&self, content: &str) -> Option<Caught> {
+            (content.chars().any(char::is_alphabetic) && content == content.to_uppercase())
+                .then(|| Caught { reason: String::from("shouting"), masked: content.to_lowercase() })
+        }
+    }
+
+    #[test]
+    fn test_content_filters() {
+        let config = ServerConfig::parse("test.toml", "[filters]\nblocklist = [\"darn\", \"fr[ie]ck\"]\nlinks = true\nmax_length = 24\n\n[filters.rooms.kids]\nblocklist = [\"darn\"]\nblocklist_action = \"reject\"\n").unwrap();
+        assert!(config.validate().is_ok());
+        let filters = config.filters.to_content_filters().unwrap();
+
+        assert_eq!(filters.apply(None, "Darn, frick it").unwrap().content, "****, ***** it");
+        assert_eq!(filters.apply(None, "see https://example.com").unwrap().content, "see [link removed]");
+        assert_eq!(filters.apply(Some("lobby"), "this message is far too long").unwrap_err(), "message is longer than 24 characters");
+        // rooms with filters of their own don't use the server's.
+        assert_eq!(filters.apply(Some("kids"), "darn").unwrap_err(), "message contains a blocked word");
+        assert_eq!(filters.apply(Some("kids"), "www.example.com").unwrap().content, "www.example.com");
+
+        let mut chain = FilterChain::default();
+        chain.add(Shouting, FilterAction::Flag);
+        assert_eq!(chain.apply("HELLO").unwrap(), Filtered { content: String::from("HELLO"), flags: vec![String::from("shouting")] });
+        assert!(chain.apply("hello").unwrap().flags.is_empty());
+
+        for invalid in ["[filters]\nblocklist = [\"(\"]\n", "[filters]\nlinks_action = \"hide\"\n", "[filters.rooms.kids.rooms.inner]\nlinks = true\n"] {
+            assert!(matches!(ServerConfig::parse("test.toml", invalid).unwrap().validate(), Err(ConfigError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn test_filtered_messages() {
+        let accounts = std::env::temp_dir().join(format!("rust-chat-filters-{}", std::process::id()));
+        let mut filters = ContentFilters::default();
+        filters.chain_mut(Some("lobby"))
+            .add(Blocklist::new(&[String::from("darn")]).unwrap(), FilterAction::Mask)
+            .add(Links::default(), FilterAction::Reject);
+        filters.chain_mut(None).add(Shouting, FilterAction::Flag);
+
+        let mut server = test_server("0.0.0.0:6019");
+        server.set_accounts_path(accounts.to_str().unwrap());
+        server.set_content_filters(filters);
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6019", "filters-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6019", "filters-0002", "bob");
+        expect_client(&mut alice, "filters-0002");
+        server.set_role("filters-0001", Role::Moderator);
+        expect_client(&mut alice, "filters-0001");
+        expect_client(&mut bob, "filters-0001");
+
+        transmit(&mut alice, "!join: room:lobby");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        transmit(&mut bob, "!join: room:lobby");
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        assert!(matches!(receive(&mut alice), Commands::Join(Some(_))));
+
+        let expect = |stream: &mut TcpStream, key: &str, value: &str| {
+            let params = match receive(stream) {
+                Commands::Message(Some(params)) | Commands::Flag(Some(params)) => params,
+                command => panic!("expected {} {}, got {:?}", key, value, command),
+            };
+            assert_eq!(params.get(key).unwrap(), value);
+            transmit(stream, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+        };
+
+        transmit(&mut bob, "!message: room:lobby content:\"darn it\"");
+        assert!(matches!(receive(&mut bob), Commands::Success(Some(_))));
+        expect(&mut alice, "content", "**** it");
+
+        transmit(&mut bob, "!message: room:lobby content:\"see www.example.com\"");
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("message contains a link"))].iter().cloned().collect())));
+
+        // direct messages use the server's filters, flagged ones reach the moderators.
+        transmit(&mut bob, "!message: to:filters-0001 content:HELLO");
+        assert!(matches!(receive(&mut bob), Commands::Success(Some(_))));
+        expect(&mut alice, "content", "HELLO");
+        expect(&mut alice, "reason", "shouting");
+        let _ = std::fs::remove_file(&accounts);
+    }
+
+    #[test]
+    fn test_message_threads() {
+        let history_path = std::env::temp_dir().join("rust-chat-server-test-threads.log");
+        let _ = std::fs::remove_file(&history_path);
+
+        let mut server = test_server("0.0.0.0:6025");
+        server.set_history_path(history_path.to_str().unwrap());
+        server.start().unwrap();
+
+        register_client("127.0.0.1:6025", "thread-0002", "bob");
+        let mut alice = connect_client("127.0.0.1:6025", "thread-0001", "alice");
+
+        // bob is offline so the messages are queued rather than waiting on acks.
+        let mut send = |data: &str| {
+            transmit(&mut alice, data);
+            match receive(&mut alice) {
+                Commands::Success(Some(params)) => params.get("id").unwrap().clone(),
+                command => panic!("expected success, got {:?}", command),
+            }
+        };
+        let root = send("!message: to:thread-0002 content:\"who has the report?\"");
+        let reply = send(format!("!message: to:thread-0002 content:\"not me\" replyTo:{}", root).as_str());
+        // a reply to a reply stays in the thread of the first message.
+        let nested = send(format!("!message: to:thread-0002 content:\"me neither\" replyTo:{}", reply).as_str());
+
+        let unknown = |reason: &str| Commands::Error(Some([(String::from("reason"), reason.to_string())].iter().cloned().collect()));
+        transmit(&mut alice, "!message: to:thread-0002 content:hello replyTo:999");
+        assert_eq!(receive(&mut alice), unknown("unknown message to reply to"));
+
+        transmit(&mut alice, format!("!thread: id:{}", nested).as_str());
+        for (id, reply_to) in [(&root, None), (&reply, Some(&root)), (&nested, Some(&reply))] {
+            match receive(&mut alice) {
+                Commands::History(Some(params)) => {
+                    assert_eq!(params.get("id"), Some(id));
+                    assert_eq!(params.get("replyTo"), reply_to);
+                    assert_eq!(params.get("thread"), reply_to.map(|_| &root));
+                },
+                command => panic!("expected a message in the thread, got {:?}", command),
+            }
+        }
+        match receive(&mut alice) {
+            Commands::Success(Some(params)) => assert_eq!(params.get("count").unwrap(), "3"),
+            command => panic!("expected end of thread, got {:?}", command),
+        }
+
+        // threads are only shown to those who can see the messages in them.
+        let mut carol = connect_client("127.0.0.1:6025", "thread-0003", "carol");
+        transmit(&mut carol, format!("!thread: id:{}", root).as_str());
+        assert_eq!(receive(&mut carol), unknown("unknown message id"));
+    }
+
+    #[test]
+    fn test_message_reactions() {
+        let history_path = std::env::temp_dir().join("rust-chat-server-test-reactions.log");
+        let _ = std::fs::remove_file(&history_path);
+
+        let mut server = test_server("0.0.0.0:6026");
+        server.set_history_path(history_path.to_str().unwrap());
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6026", "react-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6026", "react-0002", "bob");
+        expect_client(&mut alice, "react-0002");
+
+        transmit(&mut alice, "!message: to:react-0002 content:\"lunch at noon?\"");
+        assert!(matches!(receive(&mut bob), Commands::Message(Some(_))));
+        transmit(&mut bob, "!success:");
+        let id = match receive(&mut alice) {
+            Commands::Success(Some(params)) => params.get("id").unwrap().clone(),
+            command => panic!("expected success, got {:?}", command),
+        };
+        assert!(matches!(receive(&mut alice), Commands::Receipt(Some(_))));
+
+        let change = |params: HashMap<String, String>, reaction: &str, count: &str| {
+            assert_eq!(params.get("id").unwrap(), &id);
+            assert_eq!(params.get("by").unwrap(), "react-0002");
+            assert_eq!(params.get("reaction").unwrap(), reaction);
+            assert_eq!(params.get("count").unwrap(), count);
+        };
+        let error = |reason: &str| Commands::Error(Some([(String::from("reason"), reason.to_string())].iter().cloned().collect()));
+
+        transmit(&mut bob, format!("!react: id:{} reaction:+1", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        match receive(&mut alice) {
+            Commands::React(Some(params)) => change(params, "+1", "1"),
+            command => panic!("expected a reaction, got {:?}", command),
+        }
+
+        // reacting twice changes nothing, so alice only hears about the removal.
+        transmit(&mut bob, format!("!react: id:{} reaction:+1", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        transmit(&mut bob, format!("!unreact: id:{} reaction:+1", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        match receive(&mut alice) {
+            Commands::Unreact(Some(params)) => change(params, "+1", "0"),
+            command => panic!("expected a reaction to be removed, got {:?}", command),
+        }
+
+        transmit(&mut bob, format!("!react: id:{} reaction:\"thumbs up\"", id).as_str());
+        assert_eq!(receive(&mut bob), error("reactions can't contain ',', '=' or spaces"));
+
+        transmit(&mut bob, "!react: id:999 reaction:+1");
+        assert_eq!(receive(&mut bob), error("unknown message id"));
+
+        for n in 0..MAX_REACTIONS_PER_MESSAGE {
+            transmit(&mut bob, format!("!react: id:{} reaction:r{}", id, n).as_str());
+            assert_eq!(receive(&mut bob), Commands::Success(None));
+        }
+        transmit(&mut bob, format!("!react: id:{} reaction:+1", id).as_str());
+        assert_eq!(receive(&mut bob), error("too many different reactions on this message"));
+        // the limit is on different reactions, so removing one makes room again.
+        transmit(&mut bob, format!("!unreact: id:{} reaction:r0", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        transmit(&mut bob, format!("!react: id:{} reaction:+1", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+    }
+
+    #[test]
+    fn test_message_receipts() {
+        let server = test_server("0.0.0.0:6003");
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6003", "receipt-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6003", "receipt-0002", "bob");
+        expect_client(&mut alice, "receipt-0002");
+
+        transmit(&mut alice, "!message: to:receipt-0002 content:hello");
+        assert!(matches!(receive(&mut bob), Commands::Message(Some(_))));
+        transmit(&mut bob, "!success:");
+
+        let id = match receive(&mut alice) {
+            Commands::Success(Some(params)) => params.get("id").unwrap().clone(),
+            command => panic!("expected success, got {:?}", command),
+        };
+
+        match receive(&mut alice) {
+            Commands::Receipt(Some(params)) => {
+                assert_eq!(params.get("id").unwrap(), &id);
+                assert_eq!(params.get("status").unwrap(), "delivered");
+            },
+            command => panic!("expected delivery receipt, got {:?}", command),
+        }
+
+        transmit(&mut bob, format!("!receipt: id:{} status:read", id).as_str());
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+
+        match receive(&mut alice) {
+            Commands::Receipt(Some(params)) => assert_eq!(params.get("status").unwrap(), "read"),
+            command => panic!("expected read receipt, got {:?}", command),
+        }
+
+        // only the recipient can send receipts for a message.
+        transmit(&mut alice, format!("!receipt: id:{} status:read", id).as_str());
+        assert_eq!(receive(&mut alice), Commands::Error(Some([(String::from("reason"), String::from("unknown message id"))].iter().cloned().collect())));
+    }
+
+    #[test]
+    fn test_profile_update() {
+        let server = test_server("0.0.0.0:6022");
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6022", "profile-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6022", "profile-0002", "bob");
+        expect_client(&mut alice, "profile-0002");
+
+        transmit(&mut alice, "!clientUpdate: name:alicia avatar:\"https://example.com/alicia.png\"");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        match receive(&mut bob) {
+            Commands::Client(Some(params)) => {
+                assert_eq!(params.get("name").unwrap(), "alicia");
+                assert_eq!(params.get("avatar").unwrap(), "https://example.com/alicia.png");
+                transmit(&mut bob, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+            },
+            command => panic!("expected the new profile, got {:?}", command),
+        }
+
+        let error = |reason: &str| Commands::Error(Some([(String::from("reason"), reason.to_string())].iter().cloned().collect()));
+        for (update, reason) in [
+            ("!clientUpdate: name:\"not a name!\"", "name may only contain letters, numbers, '_', '-' and '.'"),
+            ("!clientUpdate: avatar:\"ftp://example.com/a.png\"", "avatar must be an http(s) url"),
+            ("!clientUpdate: name:bob", "name is already in use"),
+            ("!clientUpdate: colour:blue", "profile update needs a name or avatar"),
+        ] {
+            transmit(&mut alice, update);
+            assert_eq!(receive(&mut alice), error(reason));
+        }
+    }
+
+    #[test]
+    fn test_presence() {
+        let server = test_server("0.0.0.0:6023");
+        server.start().unwrap();
+
+        let mut alice = connect_client("127.0.0.1:6023", "presence-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6023", "presence-0002", "bob");
+        expect_client(&mut alice, "presence-0002");
+
+        let presence = |state: &str, status: &str| Commands::Presence(Some([
+            (String::from("uuid"), String::from("presence-0001")),
+            (String::from("presence"), state.to_string()),
+            (String::from("status"), status.to_string()),
+        ].iter().cloned().collect()));
+
+        transmit(&mut alice, "!status: presence:busy status:\"in a meeting\"");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        assert_eq!(receive(&mut bob), presence("busy", "in a meeting"));
+
+        transmit(&mut alice, "!status: presence:sleeping");
+        assert_eq!(receive(&mut alice), Commands::Error(Some([(String::from("reason"), String::from("invalid presence or status"))].iter().cloned().collect())));
+
+        // invisible clients appear offline, without giving their status away.
+        transmit(&mut alice, "!status: presence:invisible");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        assert_eq!(receive(&mut bob), presence("offline", ""));
+
+        transmit(&mut bob, "!clientInfo: uuid:presence-0001");
+        match receive(&mut bob) {
+            Commands::Success(Some(params)) => {
+                assert_eq!(params.get("presence").unwrap(), "offline");
+                assert_eq!(params.get("status").unwrap(), "");
+            },
+            command => panic!("expected alice's profile, got {:?}", command),
+        }
+        // nor are they in the client list.
+        transmit(&mut bob, "!clientUpdate:");
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+        match receive(&mut bob) {
+            Commands::Client(Some(params)) => {
+                assert_eq!(params.get("uuid").unwrap(), "presence-0002");
+                transmit(&mut bob, format!("!success: ack:{}", params.get("ack").unwrap()).as_str());
+            },
+            command => panic!("expected only bob in the client list, got {:?}", command),
+        }
+        transmit(&mut bob, "!heartbeat:");
+        assert_eq!(receive(&mut bob), Commands::Success(None));
+
+        transmit(&mut bob, "!clientInfo: name:alice");
+        assert_eq!(receive(&mut bob), Commands::Error(Some([(String::from("reason"), String::from("clientInfo needs a uuid"))].iter().cloned().collect())));
+
+        transmit(&mut alice, "!status: presence:online");
+        assert_eq!(receive(&mut alice), Commands::Success(None));
+        assert_eq!(receive(&mut bob), presence("online", "in a meeting"));
+    }
+
+    #[test]
+    fn test_auto_away() {
+        let mut server = test_server("0.0.0.0:6024");
+        server.set_idle_timeout(Duration::from_secs(2));
+        server.start().unwrap();
+
+        let mut bob = connect_client("127.0.0.1:6024", "away-0002", "bob");
+        let mut alice = connect_client("127.0.0.1:6024", "away-0001", "alice");
+        expect_client(&mut bob, "away-0001");
+
+        let presence = |state: &str| Commands::Presence(Some([
+            (String::from("uuid"), String::from("away-0001")),
+            (String::from("presence"), state.to_string()),
+            (String::from("status"), String::new()),
+        ].iter().cloned().collect()));
+
+        // heartbeats don't count as activity, anything else brings alice back.
+        transmit(&mut alice, "!heartbeat:");
+        assert_eq!(receive(&mut bob), presence("away"));
+        transmit(&mut alice, "!join: room:lobby");
+        assert_eq!(receive(&mut bob), presence("online"));
+    }
+
+    #[test]
+    fn test_mentions() {
+        assert_eq!(parse_mentions("hi @bob and @carol."), vec!["bob", "carol"]);
+        assert_eq!(parse_mentions("@bob, @bob again"), vec!["bob"]);
+        assert!(parse_mentions("mail bob@example.com or @@bob").is_empty());
+        let many: Vec<String> = (0..20).map(|i| format!("@user{}", i)).collect();
+        assert_eq!(parse_mentions(&many.join(" ")).len(), MAX_MENTIONS_PER_MESSAGE);
+
+        let server = test_server("0.0.0.0:6021");
+        server.start().unwrap();
+
+        // carol has an account but isn't connected when she is mentioned.
+        let mut carol = connect_client("127.0.0.1:6021", "mention-0003", "carol");
+        transmit(&mut carol, "!disconnect:");
+        thread::sleep(Duration::from_millis(500));
+
+        let mut alice = connect_client("127.0.0.1:6021", "mention-0001", "alice");
+        let mut bob = connect_client("127.0.0.1:6021", "mention-0002", "bob");
+        expect_client(&mut alice, "mention-0002");
+        for stream in [&mut alice, &mut bob] {
+            transmit(stream, "!join: room:lobby");
+            assert_eq!(receive(stream), Commands::Success(None));
+        }
+        assert!(matches!(receive(&mut alice), Commands::Join(Some(_))));
+
+        transmit(&mut alice, "!message: room:lobby content:\"hi @bob and @carol\"");
+        assert!(matches!(receive(&mut bob), Commands::Message(Some(_))));
+        transmit(&mut bob, "!success:");
+        match receive(&mut bob) {
+            Commands::Mention(Some(params)) => {
+                assert_eq!(params.get("from").unwrap(), "mention-0001");
+                assert_eq!(params.get("room").unwrap(), "lobby");
+            },
+            command => panic!("expected a mention, got {:?}", command),
+        }
+
+        let mut carol = connect_client("127.0.0.1:6021", "mention-0003", "carol");
+        match receive(&mut carol) {
+            Commands::Mention(Some(params)) => assert_eq!(params.get("content").unwrap(), "hi @bob and @carol"),
+            command => panic!("expected the queued mention, got {:?}", command),
+        }
+
+        // names are unique, so a mention can only mean one account.
+        let mut mallory = TcpStream::connect("127.0.0.1:6021").unwrap();
+        mallory.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&mut mallory), Commands::Request(None));
+        transmit(&mut mallory, "!connect: uuid:mention-0004 name:bob host:127.0.0.1");
+        assert_eq!(receive(&mut mallory), Commands::Error(Some([(String::from("reason"), String::from("name is already in use"))].iter().cloned().collect())));
     }
 }
 
 #[cfg(test)]
 mod crypto_tests {
     use openssl::rsa::{Rsa, Padding};
-    use openssl::ssl::{SslMethod, SslAcceptor, SslStream, SslFiletype, SslConnector, SslVerifyMode};
-    use std::net::{TcpListener, TcpStream};
-    use std::sync::Arc;
-    use std::thread;
     use std::str;
 
     #[test]
@@ -203,50 +2026,4 @@ mod crypto_tests {
         let _ = rsa.public_decrypt(&mut buf, &mut buf2, Padding::PKCS1).unwrap();
         println!("after: {:?}", &buf2);
     }
-
-    #[test]
-    fn tls_handshake() {
-        // spawn the server
-        thread::spawn(|| {
-            println!("creating acceptor");
-            let mut acceptor = SslAcceptor::mozilla_modern(SslMethod::tls()).unwrap();
-            acceptor.set_private_key_file("cert.pem", SslFiletype::PEM).unwrap();
-            acceptor.set_certificate_chain_file("root.pem").unwrap();
-            acceptor.check_private_key().unwrap();
-            let acceptor = Arc::new(acceptor.build());
-
-            let listener = TcpListener::bind("0.0.0.0:6000").unwrap();
-
-            println!("entering loop");
-            loop {
-                for stream in listener.incoming() {
-                    println!("client accepted");
-                    match stream {
-                        Ok(stream) => {
-                            let acceptor = acceptor.clone();
-                            thread::spawn(move || {
-                                let mut stream = acceptor.accept(stream).unwrap();
-                                
-                                let mut buffer: [u8; 1024] = [0; 1024];
-
-                                stream.ssl_read(&mut buffer).unwrap();
-                                let result = str::from_utf8(&buffer).unwrap();
-                                if buffer == "echo".as_bytes() {
-                                    let _ = stream.ssl_write("echo".as_bytes()).unwrap();
-                                }
-                            });
-                        }
-                        Err(e) => { /* connection failed */ }
-                    }
-                }
-            }
-        });
-
-        let connector = SslConnector::builder(SslMethod::tls()).unwrap().build();
-
-        let stream = TcpStream::connect("localhost:6000").unwrap();
-        let mut stream = connector.connect("127.0.0.1", stream).unwrap();
-
-        let _ = stream.ssl_write("echo".as_bytes()).unwrap();
-    }
 }
\ No newline at end of file
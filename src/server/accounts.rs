@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    fs,
+    fs::OpenOptions,
+    io::prelude::*,
+    io::{BufReader, Error},
+    path::PathBuf,
+};
+
+use log::info;
+use openssl::{memcmp, rand::rand_bytes, sha::sha256};
+
+use crate::{
+    server::{roles::Role, transfers::hex_encode},
+    commands::Commands,
+};
+
+pub const DEFAULT_ACCOUNTS_PATH: &str = "data/accounts";
+
+/// A user the server has seen, whether or not they are connected.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub uuid: String,
+    pub username: String,
+    pub role: Role,
+    /// Hash of the secret the client was given the first time it connected.
+    secret: Option<String>,
+}
+
+impl Account {
+    fn to_params(&self) -> HashMap<String, String> {
+        [
+            (String::from("uuid"), self.uuid.clone()),
+            (String::from("name"), self.username.clone()),
+            (String::from("role"), self.role.as_str().to_string()),
+        ].iter().cloned().chain(self.secret.clone().map(|secret| (String::from("secret"), secret))).collect()
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Option<Self> {
+        Some(Account {
+            uuid: params.get("uuid")?.clone(),
+            username: params.get("name")?.clone(),
+            // accounts saved before roles existed are plain users.
+            role: params.get("role").and_then(|role| role.parse().ok()).unwrap_or_default(),
+            secret: params.get("secret").cloned(),
+        })
+    }
+
+    fn hash(secret: &str) -> String {
+        hex_encode(&sha256(secret.as_bytes()))
+    }
+}
+
+/// The outcome of a client connecting as an account.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignIn {
+    /// The client gave the account's secret.
+    Verified,
+    /// The account had no secret yet, this one has to be given back on every later connect.
+    Issued(String),
+    Refused,
+}
+
+/// Persistent store of every account that has connected to the server.
+///
+/// The store is small, so it is kept in memory and the file is
+/// rewritten whenever an account changes.
+#[derive(Debug)]
+pub struct Accounts {
+    path: PathBuf,
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let mut accounts = Accounts {
+            path: PathBuf::from(path),
+            accounts: HashMap::new(),
+        };
+
+        if accounts.path.exists() {
+            for line in BufReader::new(fs::File::open(&accounts.path)?).lines() {
+                if let Commands::Client(Some(params)) = Commands::from(line?) {
+                    if let Some(account) = Account::from_params(&params) {
+                        accounts.accounts.insert(account.uuid.clone(), account);
+                    }
+                }
+            }
+        }
+
+        info!("accounts: loaded {} accounts", accounts.accounts.len());
+        Ok(accounts)
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<&Account> {
+        self.accounts.get(uuid)
+    }
+
+    pub fn find_by_username(&self, username: &str) -> Option<&Account> {
+        self.accounts.values().find(|account| account.username == username)
+    }
+
+    /// Whether an account other than `uuid` goes by `username`.
+    pub fn is_taken(&self, username: &str, uuid: &str) -> bool {
+        self.accounts.values().any(|account| account.username == username && account.uuid != uuid)
+    }
+
+    /// The role of an account, unknown accounts are plain users.
+    pub fn role(&self, uuid: &str) -> Role {
+        self.accounts.get(uuid).map(|account| account.role).unwrap_or_default()
+    }
+
+    /// Check the secret a client connected with, recording the username it
+    /// connected as once it is verified. Accounts seen for the first time,
+    /// and those saved before they had secrets, are issued one and lose any
+    /// role until it is given back, since anyone could have claimed them.
+    pub fn sign_in(&mut self, uuid: &str, username: &str, secret: Option<&str>) -> Result<SignIn, Error> {
+        if let Some(hash) = self.accounts.get(uuid).and_then(|account| account.secret.as_ref()) {
+            let verified = secret.map(|secret| memcmp::eq(Account::hash(secret).as_bytes(), hash.as_bytes())).unwrap_or(false);
+            if !verified {
+                return Ok(SignIn::Refused);
+            }
+            self.update(uuid, username)?;
+            return Ok(SignIn::Verified);
+        }
+
+        let mut bytes = [0; 32];
+        rand_bytes(&mut bytes)?;
+        let secret = hex_encode(&bytes);
+        let account = self.accounts.entry(uuid.to_string()).or_insert_with(|| Account { uuid: uuid.to_string(), username: username.to_string(), role: Role::User, secret: None });
+        if account.role != Role::User {
+            info!("accounts: {} had no secret, dropping its {} role", uuid, account.role.as_str());
+        }
+        account.username = username.to_string();
+        account.role = Role::User;
+        account.secret = Some(Account::hash(&secret));
+        self.save()?;
+        Ok(SignIn::Issued(secret))
+    }
+
+    /// Record the current username of an account, creating it if needed.
+    pub fn update(&mut self, uuid: &str, username: &str) -> Result<(), Error> {
+        match self.accounts.get_mut(uuid) {
+            Some(account) if account.username == username => return Ok(()),
+            Some(account) => account.username = username.to_string(),
+            None => {
+                self.accounts.insert(uuid.to_string(), Account { uuid: uuid.to_string(), username: username.to_string(), role: Role::User, secret: None });
+            },
+        }
+        self.save()
+    }
+
+    /// Give an account a role. Returns false if the account is unknown.
+    pub fn set_role(&mut self, uuid: &str, role: Role) -> Result<bool, Error> {
+        match self.accounts.get_mut(uuid) {
+            Some(account) => account.role = role,
+            None => return Ok(false),
+        }
+        self.save().map(|_| true)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&self.path)?;
+        for account in self.accounts.values() {
+            writeln!(file, "{}", Commands::Client(Some(account.to_params())).to_string())?;
+        }
+        file.flush()
+    }
+}
@@ -0,0 +1,47 @@
+use std::{collections::HashMap, time::Duration};
+
+use openssl::{memcmp, sha::sha256};
+
+use crate::server::{
+    bans::{Ban, BanTarget},
+    server_profile::ServerMessages,
+};
+
+/// Check the token sent with an `!admin:` request. An empty `expected`
+/// token lets nobody in. Digests are compared so the time taken doesn't
+/// depend on how much of the token was right.
+pub fn token_matches(expected: &str, given: Option<&str>) -> bool {
+    match given {
+        Some(given) if !expected.is_empty() => memcmp::eq(&sha256(expected.as_bytes()), &sha256(given.as_bytes())),
+        _ => false,
+    }
+}
+
+/// Turn an admin request, such as `!admin: action:kick uuid:<uuid> reason:spam`,
+/// into the message for the server thread. Bans and mutes last for
+/// `duration` seconds when it is given, and a ban of a uuid with
+/// `linkAddress:true` bans the address the client connects from too.
+pub fn admin_message(params: &HashMap<String, String>) -> Result<ServerMessages, &'static str> {
+    let param = |key: &str| params.get(key).filter(|value| !value.is_empty()).cloned();
+    let reason = param("reason").unwrap_or_else(|| String::from("no reason given"));
+    let duration = match param("duration") {
+        Some(duration) => Some(duration.parse::<u64>().map_err(|_| "duration must be a number of seconds")?),
+        None => None,
+    };
+
+    match params.get("action").map(String::as_str) {
+        Some("kick") => Ok(ServerMessages::Kick(param("uuid").ok_or("kick needs a uuid")?, reason)),
+        Some("ban") => Ok(ServerMessages::Ban(Ban::new(BanTarget::from_params(params)?, &reason, duration), param("linkAddress").as_deref() == Some("true"))),
+        Some("unban") => Ok(ServerMessages::Unban(BanTarget::from_params(params)?)),
+        Some("mute") => Ok(ServerMessages::Mute(param("room").ok_or("mute needs a room")?, param("uuid").ok_or("mute needs a uuid")?, duration.map(Duration::from_secs))),
+        Some("unmute") => Ok(ServerMessages::Unmute(param("room").ok_or("unmute needs a room")?, param("uuid").ok_or("unmute needs a uuid")?)),
+        Some("announce") => Ok(ServerMessages::Announce(param("content").ok_or("announce needs content")?)),
+        Some("role") => {
+            let role = param("role").ok_or("role needs a role")?.parse().map_err(|_| "role must be one of owner, admin, moderator or user")?;
+            Ok(ServerMessages::SetRole(param("uuid").ok_or("role needs a uuid")?, role))
+        },
+        Some("removeMember") => Ok(ServerMessages::RemoveMember(param("room").ok_or("removeMember needs a room")?, param("uuid").ok_or("removeMember needs a uuid")?)),
+        Some("shutdown") => Ok(ServerMessages::Shutdown),
+        _ => Err("unknown admin action"),
+    }
+}
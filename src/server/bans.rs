@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    fs::OpenOptions,
+    io::prelude::*,
+    io::{BufReader, Error},
+    net::IpAddr,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+
+use crate::commands::Commands;
+
+pub const DEFAULT_BANS_PATH: &str = "data/bans";
+
+/// Who a ban applies to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+    Uuid(String),
+    Username(String),
+    /// An address and how many leading bits of it must match, so a whole
+    /// network can be banned with CIDR notation.
+    Address(IpAddr, u8),
+}
+
+impl BanTarget {
+    /// Read the target from the `uuid`, `username` or `address` parameter.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, &'static str> {
+        if let Some(uuid) = params.get("uuid") {
+            Ok(BanTarget::Uuid(uuid.clone()))
+        } else if let Some(username) = params.get("username") {
+            Ok(BanTarget::Username(username.clone()))
+        } else if let Some(address) = params.get("address") {
+            BanTarget::parse_address(address).ok_or("address must be an ip address or network, e.g. 10.0.0.0/8")
+        } else {
+            Err("ban needs a uuid, username or address")
+        }
+    }
+
+    fn parse_address(data: &str) -> Option<Self> {
+        let (ip, prefix) = match data.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (data.parse::<IpAddr>().ok()?, None),
+        };
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        match prefix {
+            Some(prefix) if prefix > bits => None,
+            Some(prefix) => Some(BanTarget::Address(ip, prefix)),
+            None => Some(BanTarget::Address(ip, bits)),
+        }
+    }
+
+    fn to_param(&self) -> (String, String) {
+        match self {
+            BanTarget::Uuid(uuid) => (String::from("uuid"), uuid.clone()),
+            BanTarget::Username(username) => (String::from("username"), username.clone()),
+            BanTarget::Address(ip, prefix) if (ip.is_ipv4() && *prefix == 32) || *prefix == 128 => (String::from("address"), ip.to_string()),
+            BanTarget::Address(ip, prefix) => (String::from("address"), format!("{}/{}", ip, prefix)),
+        }
+    }
+
+    /// Whether a connection from `ip`, by the client with `uuid` and
+    /// `username` if they are known yet, is covered by this target.
+    pub fn matches(&self, uuid: Option<&str>, username: Option<&str>, ip: IpAddr) -> bool {
+        match self {
+            BanTarget::Uuid(banned) => uuid == Some(banned.as_str()),
+            BanTarget::Username(banned) => username == Some(banned.as_str()),
+            BanTarget::Address(network, prefix) => match (BanTarget::canonical(*network), BanTarget::canonical(ip)) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => BanTarget::same_prefix(u32::from(network) as u128, u32::from(ip) as u128, *prefix, 32),
+                (IpAddr::V6(network), IpAddr::V6(ip)) => BanTarget::same_prefix(u128::from(network), u128::from(ip), *prefix, 128),
+                _ => false,
+            },
+        }
+    }
+
+    /// IPv4 clients of a dual stack listener show up as mapped IPv6 addresses.
+    fn canonical(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        }
+    }
+
+    fn same_prefix(network: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+        let shift = (bits - prefix.min(bits)) as u32;
+        network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (key, value) = self.to_param();
+        write!(f, "{} {}", key, value)
+    }
+}
+
+/// A ban, with an expiry time in seconds since the epoch if it is timed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    pub created: u64,
+    pub expires: Option<u64>,
+    /// The uuid whose ban this was added with, it is lifted along with it.
+    pub linked: Option<String>,
+}
+
+impl Ban {
+    pub fn new(target: BanTarget, reason: &str, duration: Option<u64>) -> Self {
+        let now = now();
+        Ban { target, reason: reason.to_string(), created: now, expires: duration.map(|duration| now + duration), linked: None }
+    }
+
+    /// A ban of `ip` that comes and goes with this ban of a uuid.
+    pub fn linked_address(&self, ip: IpAddr) -> Option<Self> {
+        match &self.target {
+            BanTarget::Uuid(uuid) => Some(Ban {
+                target: BanTarget::Address(ip, if ip.is_ipv4() { 32 } else { 128 }),
+                linked: Some(uuid.clone()),
+                ..self.clone()
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params: HashMap<String, String> = [
+            self.target.to_param(),
+            (String::from("reason"), self.reason.clone()),
+            (String::from("created"), self.created.to_string()),
+        ].iter().cloned().collect();
+        if let Some(expires) = self.expires {
+            params.insert(String::from("expires"), expires.to_string());
+        }
+        if let Some(linked) = &self.linked {
+            params.insert(String::from("linked"), linked.clone());
+        }
+        params
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Option<Self> {
+        Some(Ban {
+            target: BanTarget::from_params(params).ok()?,
+            reason: params.get("reason").cloned().unwrap_or_default(),
+            created: params.get("created")?.parse().ok()?,
+            expires: params.get("expires").and_then(|expires| expires.parse().ok()),
+            linked: params.get("linked").cloned(),
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+
+    /// The error sent to a banned client.
+    pub fn refusal(&self) -> Commands {
+        let mut params: HashMap<String, String> = [(String::from("reason"), String::from("banned"))].iter().cloned().collect();
+        if let Some(expires) = self.expires {
+            params.insert(String::from("until"), expires.to_string());
+        }
+        Commands::Error(Some(params))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Persistent list of bans, rewritten whenever a ban is added or removed.
+#[derive(Debug)]
+pub struct Bans {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl Bans {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let mut bans = Bans {
+            path: PathBuf::from(path),
+            bans: Vec::new(),
+        };
+
+        if bans.path.exists() {
+            for line in BufReader::new(fs::File::open(&bans.path)?).lines() {
+                if let Commands::Ban(Some(params)) = Commands::from(line?) {
+                    if let Some(ban) = Ban::from_params(&params) {
+                        bans.bans.push(ban);
+                    }
+                }
+            }
+        }
+
+        info!("bans: loaded {} bans", bans.bans.len());
+        Ok(bans)
+    }
+
+    pub fn list(&self) -> &[Ban] {
+        &self.bans
+    }
+
+    /// Add a ban, replacing any existing ban of the same target.
+    pub fn add(&mut self, ban: Ban) -> Result<(), Error> {
+        self.bans.retain(|existing| existing.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Lift the ban of a target, and any bans linked to it. Returns false
+    /// if it wasn't banned.
+    pub fn remove(&mut self, target: &BanTarget) -> Result<bool, Error> {
+        let count = self.bans.len();
+        let linked = match target {
+            BanTarget::Uuid(uuid) => Some(uuid.as_str()),
+            _ => None,
+        };
+        self.bans.retain(|ban| ban.target != *target && (linked.is_none() || ban.linked.as_deref() != linked));
+        if self.bans.len() == count {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Drop timed bans that have run out.
+    pub fn expire(&mut self) -> Result<(), Error> {
+        let now = now();
+        if !self.bans.iter().any(|ban| ban.is_expired(now)) {
+            return Ok(());
+        }
+        self.bans.retain(|ban| !ban.is_expired(now));
+        self.save()
+    }
+
+    /// The ban covering a connection, if any. Only the address is known
+    /// before the client has said who it is.
+    pub fn find(&self, uuid: Option<&str>, username: Option<&str>, ip: IpAddr) -> Option<&Ban> {
+        let now = now();
+        self.bans.iter().find(|ban| !ban.is_expired(now) && ban.target.matches(uuid, username, ip))
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&self.path)?;
+        for ban in self.bans.iter() {
+            writeln!(file, "{}", Commands::Ban(Some(ban.to_params())).to_string())?;
+        }
+        file.flush()
+    }
+}
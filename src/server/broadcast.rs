@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use crate::{
+    server::{
+        accounts::Accounts,
+        client::client_profile::{Client, Presence},
+        inbox::{Inbox, InboxError},
+        rooms::Rooms,
+    },
+    commands::Commands,
+};
+
+/// Queue a command for every connected client. Commands that don't fit
+/// in a client's queue are dropped, so nothing that has to arrive goes
+/// through here.
+pub fn broadcast(clients: &HashMap<String, Client>, command: &Commands) {
+    for client in clients.values() {
+        let _ = client.sender.send(command.clone());
+    }
+}
+
+/// Queue a command for every connected client except `uuid`,
+/// usually the client the command is about.
+pub fn broadcast_except(clients: &HashMap<String, Client>, uuid: &str, command: &Commands) {
+    for (_k, client) in clients.iter().filter(|(k, _client)| *k != uuid) {
+        let _ = client.sender.send(command.clone());
+    }
+}
+
+/// Queue a command for the connected members of a room except `uuid`.
+pub fn broadcast_room(clients: &HashMap<String, Client>, rooms: &Rooms, room: &str, uuid: &str, command: &Commands) {
+    send_to(clients, &rooms.members(room), uuid, command);
+}
+
+/// Tell everyone else about a client's profile. Invisible clients are
+/// left out of everyone else's client list, so they aren't announced.
+pub fn announce_client(clients: &HashMap<String, Client>, uuid: &str) {
+    match clients.get(uuid) {
+        Some(client) if client.get_presence() != Presence::Invisible => broadcast_except(clients, uuid, &Commands::Client(Some(client.get_params()))),
+        _ => {},
+    }
+}
+
+/// Queue a command for each of `recipients` except `uuid`. Returns the
+/// recipients it couldn't be queued for, because they aren't connected
+/// or their queue is full.
+pub fn send_to(clients: &HashMap<String, Client>, recipients: &[String], uuid: &str, command: &Commands) -> Vec<String> {
+    let mut missed = Vec::new();
+    for recipient in recipients.iter().filter(|recipient| *recipient != uuid) {
+        match clients.get(recipient) {
+            Some(client) if client.sender.send(command.clone()).is_ok() => {},
+            _ => missed.push(recipient.clone()),
+        }
+    }
+    missed
+}
+
+/// Queue a command for a client, keeping it in the client's inbox instead
+/// when the client isn't connected or its queue is full. Only clients with
+/// an account get an inbox, so made up uuids can't fill the disk.
+pub fn deliver(clients: &HashMap<String, Client>, accounts: &Accounts, inbox: &Inbox, uuid: &str, command: Commands) -> Result<(), InboxError> {
+    let command = match clients.get(uuid) {
+        Some(client) => match client.sender.send(command) {
+            Ok(()) => return Ok(()),
+            Err(command) => command,
+        },
+        None if accounts.get(uuid).is_none() => return Err(InboxError::UnknownRecipient),
+        None => command,
+    };
+    inbox.store(uuid, &command)
+}
+
+/// The command telling room members that a client joined or left.
+pub fn membership(room: &str, uuid: &str, joined: bool) -> Commands {
+    let params: HashMap<String, String> = [(String::from("room"), room.to_string()), (String::from("uuid"), uuid.to_string())].iter().cloned().collect();
+    if joined {
+        Commands::Join(Some(params))
+    } else {
+        Commands::Leave(Some(params))
+    }
+}
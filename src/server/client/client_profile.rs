@@ -3,20 +3,17 @@ extern crate regex;
 use std::{
     sync::Arc,
     sync::Mutex,
-    net::{Shutdown, TcpStream},
+    net::{IpAddr, Shutdown, TcpStream},
     io::prelude::*,
     io::Error,
     //collections::HashMap,
     time::{Instant, Duration},
+    collections::{BTreeMap, HashMap, VecDeque},
+    str::FromStr,
     io,
 };
 
-use crossbeam::{
-    Sender,
-    Receiver,
-    TryRecvError,
-    unbounded
-};
+use crossbeam::Sender;
 
 use openssl::rsa::Rsa;
 use log::info;
@@ -25,43 +22,149 @@ use crate::{
     server::{
         //server_profile::Server,
         server_profile::ServerMessages,
+        client::outbound::OutboundQueue,
+        client::rate_limit::{RateLimiter, RateLimits, Verdict},
+        roles::Role,
     },
-    commands::Commands
+    commands::{Commands, split_commands}
 
 };
 
 //use parking_lot::FairMutex;
 //use dashmap::DashMap;
 
+/// How long a client can go without activity before it is marked away.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+pub const MAX_STATUS_LENGTH: usize = 128;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const MAX_AVATAR_LENGTH: usize = 256;
+/// How long to wait for a command to be acknowledged before sending it again.
+pub const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(2);
+/// How many times a command is sent before giving up on it.
+pub const MAX_TRANSMIT_ATTEMPTS: u8 = 3;
+/// How many commands can be waiting for acknowledgement at once.
+pub const MAX_UNACKED: usize = 32;
+/// How much can be waiting for the client's socket to take it before the
+/// client is dropped for not reading.
+pub const MAX_OUTGOING_BYTES: usize = 256 * 1024;
+/// How many reads a client gets each pass, so one sending a lot can't hold up the rest.
+const MAX_READS_PER_PASS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Presence {
+    Online,
+    Away,
+    Busy,
+    Invisible,
+}
+
+impl Presence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Presence::Online => "online",
+            Presence::Away => "away",
+            Presence::Busy => "busy",
+            Presence::Invisible => "invisible",
+        }
+    }
+
+    /// The presence other clients get to see, invisible clients appear offline.
+    pub fn visible_str(&self) -> &'static str {
+        match self {
+            Presence::Invisible => "offline",
+            presence => presence.as_str(),
+        }
+    }
+}
+
+impl FromStr for Presence {
+    type Err = ();
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "online" => Ok(Presence::Online),
+            "away" => Ok(Presence::Away),
+            "busy" => Ok(Presence::Busy),
+            "invisible" => Ok(Presence::Invisible),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A command sent to the client that hasn't been acknowledged yet.
+#[derive(Debug)]
+struct Unacked {
+    command: Commands,
+    sent: Instant,
+    attempts: u8,
+}
+
 #[derive(Debug)]
 pub struct Client {
     uuid: String,
     username: String,
     address: String,
+    avatar: String,
+
+    presence: Presence,
+    status: String,
+    role: Role,
+    // set when the client was marked away for being idle rather than by choice.
+    auto_away: bool,
+    last_activity: Instant,
+    idle_timeout: Duration,
 
     last_heartbeat: Arc<Mutex<Instant>>,
 
     stream_arc: Arc<Mutex<TcpStream>>,
+    // commands read from the client that haven't been handled yet.
+    incoming: VecDeque<Commands>,
+    // the start of a command cut off by the end of the last read.
+    partial: Vec<u8>,
+    // data the socket hasn't taken yet, sent before anything else.
+    outgoing: Vec<u8>,
 
-    pub sender: Sender<Commands>,
-    receiver: Receiver<Commands>,
+    pub sender: OutboundQueue,
+    // commands waiting for a `Commands::Success` with their ack number.
+    unacked: BTreeMap<u64, Unacked>,
+    next_ack: u64,
+    // commands from the inbox, which stay there until the client acknowledges them.
+    from_inbox: Vec<Commands>,
+    limiter: RateLimiter,
 
     server_sender: Sender<ServerMessages>,
 }
 
 impl Client {
-    pub fn new(stream: TcpStream, server_sender: Sender<ServerMessages>, uuid: &str, username: &str, address: &str) -> Self {
-        let (sender, receiver): (Sender<Commands>, Receiver<Commands>) = unbounded();
-        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(stream: TcpStream, server_sender: Sender<ServerMessages>, sender: OutboundQueue, limiter: RateLimiter, uuid: &str, username: &str, address: &str, idle_timeout: Duration) -> Self {
+        // the server thread handles every client in turn, so it never waits on one.
+        if let Err(e) = stream.set_nonblocking(true) {
+            println!("client: failed to make the connection for {} non-blocking: {}", uuid, e);
+        }
 
         Client {
             stream_arc: Arc::new(Mutex::new(stream)),
+            incoming: VecDeque::new(),
+            partial: Vec::new(),
+            outgoing: Vec::new(),
             uuid: uuid.to_string(),
             username: username.to_string(),
             address: address.to_string(),
+            avatar: String::new(),
+
+            presence: Presence::Online,
+            status: String::new(),
+            role: Role::User,
+            auto_away: false,
+            last_activity: Instant::now(),
+            idle_timeout,
 
             sender,
-            receiver,
+            unacked: BTreeMap::new(),
+            next_ack: 0,
+            from_inbox: Vec::new(),
+            limiter,
 
             server_sender,
 
@@ -70,7 +173,7 @@ impl Client {
     }
 
     #[allow(dead_code)]
-    pub fn get_sender(&self) -> &Sender<Commands> {
+    pub fn get_sender(&self) -> &OutboundQueue {
         &self.sender
     }
     
@@ -89,95 +192,440 @@ impl Client {
         self.address.clone()
     }
 
+    #[allow(dead_code)]
+    pub fn get_avatar(&self) -> String {
+        self.avatar.clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_presence(&self) -> Presence {
+        self.presence
+    }
+
+    #[allow(dead_code)]
+    pub fn get_status(&self) -> String {
+        self.status.clone()
+    }
+
+    /// The status other clients get to see, invisible clients appear
+    /// offline so their status is hidden as well.
+    fn visible_status(&self) -> String {
+        match self.presence {
+            Presence::Invisible => String::new(),
+            _ => self.get_status(),
+        }
+    }
+
+    /// The address the client is actually connecting from.
+    pub fn get_peer_ip(&self) -> Option<IpAddr> {
+        self.stream_arc.lock().unwrap().peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    /// When the client last answered a heartbeat.
+    pub fn get_last_heartbeat(&self) -> Instant {
+        *self.last_heartbeat.lock().unwrap()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_role(&self) -> Role {
+        self.role
+    }
+
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.limiter.set_limits(limits);
+    }
+
+    /// The parameters used to describe this client to others.
+    pub fn get_params(&self) -> HashMap<String, String> {
+        [
+            (String::from("uuid"), self.get_uuid()),
+            (String::from("name"), self.get_username()),
+            (String::from("host"), self.get_address()),
+            (String::from("avatar"), self.get_avatar()),
+            (String::from("presence"), self.presence.visible_str().to_string()),
+            (String::from("status"), self.visible_status()),
+            (String::from("role"), self.role.as_str().to_string()),
+        ].iter().cloned().collect()
+    }
+
+    /// Apply the `name` and `avatar` fields of a profile update,
+    /// nothing is changed unless every field is valid.
+    pub fn update_profile(&mut self, params: &HashMap<String, String>) -> Result<(), &'static str> {
+        if !params.contains_key("name") && !params.contains_key("avatar") {
+            return Err("profile update needs a name or avatar");
+        }
+        if let Some(name) = params.get("name") {
+            Client::validate_username(name)?;
+        }
+        if let Some(avatar) = params.get("avatar") {
+            let is_url = avatar.starts_with("https://") || avatar.starts_with("http://");
+            if !(avatar.is_empty() || is_url) || avatar.len() > MAX_AVATAR_LENGTH {
+                return Err("avatar must be an http(s) url");
+            }
+        }
+
+        if let Some(name) = params.get("name") {
+            self.username = name.clone();
+        }
+        if let Some(avatar) = params.get("avatar") {
+            self.avatar = avatar.clone();
+        }
+        Ok(())
+    }
+
+    pub fn validate_username(name: &str) -> Result<(), &'static str> {
+        if name.is_empty() || name.chars().count() > MAX_USERNAME_LENGTH {
+            Err("name must be between 1 and 32 characters")
+        } else if !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            Err("name may only contain letters, numbers, '_', '-' and '.'")
+        } else {
+            Ok(())
+        }
+    }
+
     // TODO: - add heartbeat timer.
     pub fn handle_connection(&mut self) {
-        let mut buffer = [0; 1024];
-
         // TODO: - Check heartbeat
         {
             info!("heartbeat")
         }
+
+        // the queue or the socket filled up while the client wasn't reading, so it is dropped.
+        if self.sender.is_overflowed() || self.outgoing.len() > MAX_OUTGOING_BYTES {
+            self.outgoing.clear();
+            let params: HashMap<String, String> = [(String::from("reason"), String::from("too many unread messages"))].iter().cloned().collect();
+            self.transmit_data(Commands::Error(Some(params)).to_string().as_str());
+            let _ = self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone()));
+            let _ = self.stream_arc.lock().unwrap().shutdown(Shutdown::Both);
+            return;
+        }
+
+        if self.presence == Presence::Online && self.last_activity.elapsed() >= self.idle_timeout {
+            self.presence = Presence::Away;
+            self.auto_away = true;
+            self.announce_presence();
+        }
         
         info!("{}: handling connection", self.uuid);
-        match self.read_data(&mut buffer) {
-            Ok(command) => {
-                // match incomming commands
-                println!("command");
-                match command {
-                    Commands::Disconnect(None) => {
-                        self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone())).expect("sending message to server failed");
-                        self.stream_arc.lock().unwrap().shutdown(Shutdown::Both).expect("shutdown call failed");
-                    },
-                    Commands::HeartBeat(None) => {
-                        *self.last_heartbeat.lock().unwrap() = Instant::now();
-                        self.transmit_data(Commands::Success(None).to_string().as_str());
+        // nothing read is fine, the error only matters once the connection is gone.
+        let _ = self.read_data();
+        while let Some(command) = self.incoming.pop_front() {
+            self.handle_command(command);
+        }
+
+        println!("buffer");
+        // hand the client as much of its queue as its socket will take, nothing
+        // more is taken while too many commands are waiting for acknowledgement.
+        self.flush();
+        while self.outgoing.is_empty() && self.unacked.len() < MAX_UNACKED {
+            let mut command = match self.sender.pop() {
+                Some(command) => command,
+                None => break,
+            };
+            match Client::ack_params(&mut command) {
+                Some(params) => {
+                    self.next_ack += 1;
+                    params.insert(String::from("ack"), self.next_ack.to_string());
+                    self.transmit_data(command.to_string().as_str());
+                    self.unacked.insert(self.next_ack, Unacked { command, sent: Instant::now(), attempts: 1 });
+                },
+                // replies from the server don't need to be acknowledged
+                None => self.transmit_data(command.to_string().as_str()),
+            }
+        }
+
+        // resending is pointless while the socket hasn't taken what was sent.
+        if self.outgoing.is_empty() {
+            self.retransmit();
+        }
+        println!("---Client Thread Exit---");
+    }    
+
+    fn handle_command(&mut self, command: Commands) {
+        // match incomming commands
+        println!("command");
+        match self.limiter.check(&command) {
+            Verdict::Allow => {},
+            Verdict::Throttle(wait) => {
+                let params: HashMap<String, String> = [
+                    (String::from("reason"), String::from("rate limited")),
+                    (String::from("retry"), wait.as_millis().to_string()),
+                ].iter().cloned().collect();
+                self.transmit_data(Commands::Error(Some(params)).to_string().as_str());
+                return;
+            },
+            Verdict::Mute(duration) => {
+                let params: HashMap<String, String> = [
+                    (String::from("reason"), String::from("muted for flooding")),
+                    (String::from("retry"), duration.as_millis().to_string()),
+                ].iter().cloned().collect();
+                self.transmit_data(Commands::Error(Some(params)).to_string().as_str());
+                return;
+            },
+            Verdict::Drop => return,
+            Verdict::Disconnect => {
+                self.incoming.clear();
+                let _ = self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone()));
+                self.kick("flooding");
+                return;
+            },
+        }
+
+        match command {
+            Commands::HeartBeat(_) | Commands::Success(_) | Commands::Error(_) => {},
+            _ => self.mark_active(),
+        }
+
+        match command {
+            Commands::Disconnect(None) => {
+                self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone())).expect("sending message to server failed");
+                self.stream_arc.lock().unwrap().shutdown(Shutdown::Both).expect("shutdown call failed");
+            },
+            Commands::Success(params) => {
+                self.acknowledge(params.as_ref());
+            },
+            Commands::HeartBeat(None) => {
+                *self.last_heartbeat.lock().unwrap() = Instant::now();
+                self.transmit_data(Commands::Success(None).to_string().as_str());
+            },
+            Commands::ClientUpdate(None) => {
+                self.transmit_data(Commands::Success(None).to_string().as_str());
+                let _ = self.server_sender.send(ServerMessages::RequestUpdate(self.uuid.clone()));
+            },
+            Commands::ClientUpdate(Some(params)) => {
+                let _ = self.server_sender.send(ServerMessages::UpdateProfile(self.uuid.clone(), params));
+            },
+            Commands::ClientInfo(Some(params)) => {
+                match params.get("uuid") {
+                    Some(uuid) => { let _ = self.server_sender.send(ServerMessages::RequestInfo(self.uuid.clone(), uuid.clone())); },
+                    None => {
+                        let params: HashMap<String, String> = [(String::from("reason"), String::from("clientInfo needs a uuid"))].iter().cloned().collect();
+                        self.transmit_data(Commands::Error(Some(params)).to_string().as_str());
                     },
-                    Commands::ClientUpdate(None) => {
+                }
+            },
+            Commands::Status(Some(params)) => {
+                let presence = match params.get("presence") {
+                    Some(presence) => presence.parse::<Presence>().ok(),
+                    None => Some(self.presence),
+                };
+                let status = params.get("status").cloned().unwrap_or_else(|| self.status.clone());
+
+                match presence {
+                    Some(presence) if status.chars().count() <= MAX_STATUS_LENGTH => {
+                        self.presence = presence;
+                        self.status = status;
+                        self.auto_away = false;
                         self.transmit_data(Commands::Success(None).to_string().as_str());
-                        let _ = self.server_sender.send(ServerMessages::RequestUpdate(self.stream_arc.clone()));
-                    },
-                    Commands::ClientInfo(Some(params)) => {
-                        let uuid = params.get("uuid").unwrap();
-                        let _ = self.server_sender.send(ServerMessages::RequestInfo(uuid.clone(), self.stream_arc.clone()));
-                    },
-                    // TODO: may or may not be needed?
-                    Commands::Error(None) => {
+                        self.announce_presence();
                     },
                     _ => {
-                        self.transmit_data(Commands::Error(None).to_string().as_str());
+                        let params: HashMap<String, String> = [(String::from("reason"), String::from("invalid presence or status"))].iter().cloned().collect();
+                        self.transmit_data(Commands::Error(Some(params)).to_string().as_str());
                     },
                 }
             },
-            Err(_) => {
-                // no data was read
+            Commands::Message(Some(params)) if (params.contains_key("to") || params.contains_key("room")) && params.contains_key("content") => {
+                let _ = self.server_sender.send(ServerMessages::Message(self.uuid.clone(), params));
+            },
+            Commands::Edit(Some(params)) if params.contains_key("id") && params.contains_key("content") => {
+                let id = params.get("id").unwrap();
+                let content = params.get("content").unwrap();
+                let _ = self.server_sender.send(ServerMessages::Edit(self.uuid.clone(), id.clone(), content.clone()));
+            },
+            Commands::Delete(Some(params)) if params.contains_key("id") => {
+                let id = params.get("id").unwrap();
+                let _ = self.server_sender.send(ServerMessages::Delete(self.uuid.clone(), id.clone()));
+            },
+            Commands::React(Some(params)) if params.contains_key("id") && params.contains_key("reaction") => {
+                let id = params.get("id").unwrap();
+                let reaction = params.get("reaction").unwrap();
+                let _ = self.server_sender.send(ServerMessages::React(self.uuid.clone(), id.clone(), reaction.clone(), true));
+            },
+            Commands::Unreact(Some(params)) if params.contains_key("id") && params.contains_key("reaction") => {
+                let id = params.get("id").unwrap();
+                let reaction = params.get("reaction").unwrap();
+                let _ = self.server_sender.send(ServerMessages::React(self.uuid.clone(), id.clone(), reaction.clone(), false));
+            },
+            Commands::Search(Some(params)) => {
+                let _ = self.server_sender.send(ServerMessages::Search(self.uuid.clone(), params));
+            },
+            Commands::FileOffer(Some(params)) => {
+                let _ = self.server_sender.send(ServerMessages::FileOffer(self.uuid.clone(), params));
+            },
+            Commands::FileChunk(Some(params)) => {
+                let _ = self.server_sender.send(ServerMessages::FileChunk(self.uuid.clone(), params));
+            },
+            Commands::FileGet(Some(params)) => {
+                let _ = self.server_sender.send(ServerMessages::FileGet(self.uuid.clone(), params));
+            },
+            Commands::Admin(Some(params)) => {
+                let _ = self.server_sender.send(ServerMessages::Admin(self.uuid.clone(), params));
+            },
+            Commands::Thread(Some(params)) if params.contains_key("id") => {
+                let id = params.get("id").unwrap();
+                let _ = self.server_sender.send(ServerMessages::Thread(self.uuid.clone(), id.clone()));
+            },
+            Commands::Typing(Some(params)) if params.contains_key("to") || params.contains_key("room") => {
+                let _ = self.server_sender.send(ServerMessages::Typing(self.uuid.clone(), params));
+            },
+            Commands::Join(Some(params)) if params.contains_key("room") => {
+                let room = params.get("room").unwrap();
+                let _ = self.server_sender.send(ServerMessages::Join(self.uuid.clone(), room.clone()));
+            },
+            Commands::Leave(Some(params)) if params.contains_key("room") => {
+                let room = params.get("room").unwrap();
+                let _ = self.server_sender.send(ServerMessages::Leave(self.uuid.clone(), room.clone()));
+            },
+            Commands::Receipt(Some(params)) if params.get("status").map(String::as_str) == Some("read") && params.contains_key("id") => {
+                let id = params.get("id").unwrap();
+                let _ = self.server_sender.send(ServerMessages::Receipt(self.uuid.clone(), id.clone(), String::from("read")));
+            },
+            // TODO: may or may not be needed?
+            Commands::Error(None) => {
+            },
+            _ => {
+                self.transmit_data(Commands::Error(None).to_string().as_str());
             },
         }
+    }
 
-        println!("buffer");
-        // test to see if there is anything for the client to receive from its channel
-        match self.receiver.try_recv() {
-            /*command is on the channel*/ 
-            Ok(Commands::ClientRemove(Some(params))) => {
-                let mut retry: u8 = 3;
-                'retry_loop1: loop {
-                    if retry < 1 {
-                        self.transmit_data(Commands::Error(None).to_string().as_str());
-                        break 'retry_loop1
-                    } else {                    
-                        self.transmit_data(Commands::ClientRemove(Some(params.clone())).to_string().as_str());
-                        
-                        if self.read_data(&mut buffer).unwrap_or(Commands::Error(None)) == Commands::Success(None) {
-                            break 'retry_loop1;
-                        } else {
-                            retry -= 1;
-                        }
-                    }
-                }
-            },
-            Ok(Commands::Client(Some(params))) => {
-                let mut retry: u8 = 3;
-                'retry_loop2: loop {
-                    if retry < 1 {
-                        self.transmit_data(Commands::Error(None).to_string().as_str());
-                        break 'retry_loop2;
-                    } else {
-                        self.transmit_data(Commands::Client(Some(params.clone())).to_string().as_str());
-                        
-                        if self.read_data(&mut buffer).unwrap_or(Commands::Error(None)) == Commands::Success(None) {
-                            break 'retry_loop2;
-                        } else {
-                            retry -= 1;
-                        }
-                    }
-                }
+    fn mark_active(&mut self) {
+        self.last_activity = Instant::now();
+        if self.auto_away {
+            self.presence = Presence::Online;
+            self.auto_away = false;
+            self.announce_presence();
+        }
+    }
 
-            },
-            /*no data available yet*/
-            Err(TryRecvError::Empty) => {},
-            _ => {},
+    /// Tell the server to let everyone else know about a change in presence.
+    fn announce_presence(&self) {
+        let params: HashMap<String, String> = [
+            (String::from("uuid"), self.get_uuid()),
+            (String::from("presence"), self.presence.visible_str().to_string()),
+            (String::from("status"), self.visible_status()),
+        ].iter().cloned().collect();
+        let _ = self.server_sender.send(ServerMessages::Presence(self.uuid.clone(), params));
+    }
+
+    /// The parameters of commands that must be acknowledged by the client,
+    /// these are where the ack number is added.
+    fn ack_params(command: &mut Commands) -> Option<&mut HashMap<String, String>> {
+        match command {
+            Commands::Client(Some(params))
+            | Commands::ClientRemove(Some(params))
+            | Commands::Message(Some(params))
+            | Commands::Edit(Some(params))
+            | Commands::Delete(Some(params))
+            | Commands::FileOffer(Some(params))
+            | Commands::Announce(Some(params))
+            | Commands::Flag(Some(params)) => Some(params),
+            _ => None,
         }
-        println!("---Client Thread Exit---");
-    }    
+    }
+
+    /// Match a `Commands::Success` from the client to the command it
+    /// acknowledges, a bare success acknowledges the oldest one.
+    fn acknowledge(&mut self, params: Option<&HashMap<String, String>>) {
+        let ack = match params.and_then(|params| params.get("ack")) {
+            Some(ack) => ack.parse().ok(),
+            None => self.unacked.keys().next().copied(),
+        };
+        let command = match ack.and_then(|ack| self.unacked.remove(&ack)) {
+            Some(unacked) => Client::without_ack(unacked.command),
+            None => return,
+        };
+
+        if let Commands::Message(Some(params)) = &command {
+            if let Some(id) = params.get("id") {
+                let _ = self.server_sender.send(ServerMessages::Receipt(self.uuid.clone(), id.clone(), String::from("delivered")));
+            }
+        }
+        if let Some(position) = self.from_inbox.iter().position(|queued| *queued == command) {
+            self.from_inbox.remove(position);
+            let _ = self.server_sender.send(ServerMessages::Delivered(self.uuid.clone(), command));
+        }
+    }
+
+    /// A command as it was before an ack number was added to it.
+    fn without_ack(mut command: Commands) -> Commands {
+        if let Some(params) = Client::ack_params(&mut command) {
+            params.remove("ack");
+        }
+        command
+    }
+
+    /// Queue a command that was waiting in the inbox. Returns whether it
+    /// has to stay in the inbox until the client acknowledges it.
+    pub fn queue_from_inbox(&mut self, mut command: Commands) -> bool {
+        let acked = Client::ack_params(&mut command).is_some();
+        if self.sender.send(command.clone()).is_err() {
+            return true;
+        }
+        if acked {
+            self.from_inbox.push(command);
+        }
+        acked
+    }
+
+    /// Commands that never reached the client, to be kept in the inbox now
+    /// that it is going. Anything that came from the inbox is still there.
+    pub fn undelivered(&mut self) -> Vec<Commands> {
+        let mut commands: Vec<Commands> = std::mem::take(&mut self.unacked).into_values().map(|unacked| Client::without_ack(unacked.command)).collect();
+        while let Some(command) = self.sender.pop() {
+            commands.push(command);
+        }
+
+        let from_inbox = std::mem::take(&mut self.from_inbox);
+        commands.into_iter().filter(|command| OutboundQueue::is_durable(command) && !from_inbox.contains(command)).collect()
+    }
+
+    /// Resend commands that haven't been acknowledged in time,
+    /// giving up on them once they have been sent too many times.
+    fn retransmit(&mut self) {
+        let due: Vec<u64> = self.unacked.iter()
+            .filter(|(_, unacked)| unacked.sent.elapsed() >= RETRANSMIT_INTERVAL)
+            .map(|(ack, _)| *ack)
+            .collect();
+
+        for ack in due {
+            let unacked = self.unacked.get_mut(&ack).unwrap();
+            if unacked.attempts >= MAX_TRANSMIT_ATTEMPTS {
+                // anything worth keeping goes back in the inbox for the next time the client connects.
+                let command = Client::without_ack(self.unacked.remove(&ack).unwrap().command);
+                match self.from_inbox.iter().position(|queued| *queued == command) {
+                    Some(position) => { self.from_inbox.remove(position); },
+                    None if OutboundQueue::is_durable(&command) => {
+                        let _ = self.server_sender.send(ServerMessages::Undelivered(self.uuid.clone(), command));
+                    },
+                    None => {},
+                }
+                let params: HashMap<String, String> = [(String::from("reason"), String::from("not acknowledged")), (String::from("ack"), ack.to_string())].iter().cloned().collect();
+                self.transmit_data(Commands::Error(Some(params)).to_string().as_str());
+            } else {
+                unacked.attempts += 1;
+                unacked.sent = Instant::now();
+                let data = unacked.command.to_string();
+                self.transmit_data(data.as_str());
+            }
+        }
+    }
+
+    /// Tell the client why it is being disconnected and close the connection.
+    pub fn kick(&mut self, reason: &str) {
+        let params: HashMap<String, String> = [(String::from("reason"), reason.to_string())].iter().cloned().collect();
+        self.transmit_data(Commands::Disconnect(Some(params)).to_string().as_str());
+        let _ = self.stream_arc.lock().unwrap().shutdown(Shutdown::Both);
+    }
 
     // move into a drop perhaps
     #[allow(dead_code)]
@@ -185,32 +633,84 @@ impl Client {
         self.stream_arc.lock().unwrap().shutdown(Shutdown::Both).expect("shutdown call failed");
     }
 
-    pub fn transmit_data(&self, data: &str) {
+    /// Send data to the client, whatever the socket won't take right now
+    /// is kept and sent first on the next pass.
+    pub fn transmit_data(&mut self, data: &str) {
         println!("Transmitting data: {}", data);
+        self.outgoing.extend_from_slice(data.as_bytes());
+        self.flush();
+    }
 
-        let error_result = self.stream_arc.lock().unwrap().write_all(data.to_string().as_bytes());
-        if let Some(error) = error_result.err(){
-            match error.kind() {
-                // handle disconnections
-                io::ErrorKind::NotConnected => {
-                    let _ = self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone()));
+    /// Write waiting data until it is all sent or the socket is full.
+    fn flush(&mut self) {
+        let mut stream = self.stream_arc.lock().unwrap();
+        while !self.outgoing.is_empty() {
+            match stream.write(&self.outgoing) {
+                Ok(0) => break,
+                Ok(written) => { self.outgoing.drain(..written); },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    // handle disconnections
+                    if Client::is_disconnect(&error) {
+                        let _ = self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone()));
+                    }
+                    self.outgoing.clear();
                 },
-                _ => { },
             }
         }
     }
 
-    fn read_data(&mut self, buffer: &mut [u8; 1024]) -> Result<Commands, Error> {
-        let _ = self.stream_arc.lock().unwrap().read(buffer)?;
-        let command = Commands::from(buffer);
+    /// Read whatever the client has sent without waiting for more, the
+    /// commands in it are queued in `incoming`.
+    fn read_data(&mut self) -> Result<(), Error> {
+        let mut buffer = [0; 1024];
+        let mut result = Ok(());
+        let mut filled = false;
+        for _ in 0..MAX_READS_PER_PASS {
+            let read = match self.stream_arc.lock().unwrap().read(&mut buffer) {
+                Ok(0) => Err(Error::from(io::ErrorKind::UnexpectedEof)),
+                read => read,
+            };
+            match read {
+                Ok(size) => {
+                    self.partial.extend_from_slice(&buffer[..size]);
+                    filled = size == buffer.len();
+                    if !filled {
+                        break;
+                    }
+                },
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    // a client that went without saying goodbye is removed all the same.
+                    if Client::is_disconnect(&error) {
+                        let _ = self.server_sender.send(ServerMessages::Disconnect(self.uuid.clone()));
+                    }
+                    result = Err(error);
+                    break;
+                },
+            }
+        }
 
-        Ok(command)
+        let data = String::from_utf8_lossy(&self.partial).to_string();
+        let mut commands = split_commands(&data);
+        // the last command is only complete if the last read didn't fill the buffer.
+        let rest = if filled { commands.pop().unwrap_or("").to_string() } else { String::new() };
+        self.incoming.extend(commands.into_iter().map(|command| Commands::from(command.to_string())));
+        self.partial = rest.into_bytes();
+        result
+    }
+
+    fn is_disconnect(error: &Error) -> bool {
+        matches!(error.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected)
     }
 
 }
 
 impl ToString for Client {
-    fn to_string(&self) -> std::string::String { todo!() }
+    fn to_string(&self) -> std::string::String {
+        Commands::Client(Some(self.get_params())).to_string()
+    }
 }
 
 impl Drop for Client {
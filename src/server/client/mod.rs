@@ -1 +1,3 @@
 pub mod client_profile;
+pub mod outbound;
+pub mod rate_limit;
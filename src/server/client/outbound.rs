@@ -0,0 +1,190 @@
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use log::info;
+
+use crate::commands::Commands;
+
+/// How many commands can wait to be sent to a single client.
+pub const DEFAULT_OUTBOUND_CAPACITY: usize = 256;
+
+/// What to do when a client's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest typing or presence event to make room.
+    DropOldest,
+    /// Replace a queued presence update from the same client with the new
+    /// one, otherwise drop the oldest typing or presence event.
+    Coalesce,
+    /// Stop queueing and disconnect the client.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Counts of commands that never made it to a client, shared between
+/// every queue of a server.
+#[derive(Debug, Default)]
+pub struct OutboundStats {
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl OutboundStats {
+    #[allow(dead_code)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn disconnected(&self) -> u64 {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct Queue {
+    commands: VecDeque<Commands>,
+    dropped: u64,
+}
+
+/// Bounded queue of commands waiting to be sent to a client.
+///
+/// Cloning gives another handle to the same queue. Typing and presence
+/// events are the only commands that are ever dropped, anything else that
+/// doesn't fit is refused and counted, or overflows the queue when the
+/// policy is to disconnect.
+#[derive(Debug, Clone)]
+pub struct OutboundQueue {
+    queue: Arc<Mutex<Queue>>,
+    overflowed: Arc<AtomicBool>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: Arc<OutboundStats>,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy, stats: Arc<OutboundStats>) -> Self {
+        OutboundQueue {
+            queue: Arc::new(Mutex::new(Queue { commands: VecDeque::new(), dropped: 0 })),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            capacity: capacity.max(1),
+            policy,
+            stats,
+        }
+    }
+
+    /// Queue a command for the client. Returns the command back if it
+    /// could not be queued.
+    pub fn send(&self, command: Commands) -> Result<(), Commands> {
+        if self.is_overflowed() {
+            return Err(command);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.commands.len() < self.capacity {
+            queue.commands.push_back(command);
+            return Ok(());
+        }
+
+        match self.policy {
+            OverflowPolicy::Disconnect => {
+                info!("outbound: queue full, disconnecting slow client");
+                self.overflowed.store(true, Ordering::Relaxed);
+                self.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+                return Err(command);
+            },
+            OverflowPolicy::Coalesce => {
+                if let Some(position) = OutboundQueue::same_presence(&queue.commands, &command) {
+                    queue.commands[position] = command;
+                    self.stats.coalesced.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            },
+            OverflowPolicy::DropOldest => {},
+        }
+
+        match queue.commands.iter().position(OutboundQueue::is_ephemeral) {
+            Some(position) => {
+                queue.commands.remove(position);
+                queue.commands.push_back(command);
+                queue.dropped += 1;
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            None => {
+                queue.dropped += 1;
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                Err(command)
+            },
+        }
+    }
+
+    /// Take the next command to send to the client.
+    pub fn pop(&self) -> Option<Commands> {
+        self.queue.lock().unwrap().commands.pop_front()
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().commands.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many commands for this client have been dropped.
+    #[allow(dead_code)]
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().unwrap().dropped
+    }
+
+    /// Whether the queue filled up under the disconnect policy.
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Commands that are worth keeping for a client that never got them.
+    pub fn is_durable(command: &Commands) -> bool {
+        matches!(command, Commands::Message(_) | Commands::Edit(_) | Commands::Delete(_) | Commands::FileOffer(_) | Commands::Mention(_) | Commands::Receipt(_) | Commands::Flag(_))
+    }
+
+    fn is_ephemeral(command: &Commands) -> bool {
+        matches!(command, Commands::Typing(_) | Commands::Presence(_))
+    }
+
+    fn same_presence(commands: &VecDeque<Commands>, command: &Commands) -> Option<usize> {
+        let uuid = match command {
+            Commands::Presence(Some(params)) => params.get("uuid")?,
+            _ => return None,
+        };
+
+        commands.iter().position(|queued| match queued {
+            Commands::Presence(Some(params)) => params.get("uuid") == Some(uuid),
+            _ => false,
+        })
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    sync::Arc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::commands::Commands;
+
+/// How fast a kind of command may be sent, `rate` commands a second on
+/// average with bursts of up to `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// Rate limits for each client, and what happens to those that keep
+/// going over them. Every command counts against `commands`, and some
+/// against the limit for their kind as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    pub commands: Rate,
+    /// Messages, edits, deletes and reactions.
+    pub messages: Rate,
+    pub typing: Rate,
+    /// Requests for the client list or a client's profile, which can be
+    /// expensive to answer on a busy server.
+    pub updates: Rate,
+    /// Times a client can be throttled before it is muted.
+    pub mute_after: u32,
+    pub mute_duration: Duration,
+    /// Times a client can be throttled before it is disconnected.
+    pub disconnect_after: u32,
+    /// How long a client must stay under its limits to be forgiven.
+    pub forgive_after: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            commands: Rate { rate: 20.0, burst: 40 },
+            messages: Rate { rate: 5.0, burst: 10 },
+            typing: Rate { rate: 2.0, burst: 4 },
+            updates: Rate { rate: 0.5, burst: 3 },
+            mute_after: 10,
+            mute_duration: Duration::from_secs(30),
+            disconnect_after: 20,
+            forgive_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Counts of throttled commands, shared between every client of a server.
+#[derive(Debug, Default)]
+pub struct RateLimitStats {
+    throttled: AtomicU64,
+    muted: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl RateLimitStats {
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn muted(&self) -> u64 {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnected(&self) -> u64 {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do with a command from a client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Refuse the command, the client can try again after the duration.
+    Throttle(Duration),
+    /// The client has just been muted for the duration.
+    Mute(Duration),
+    /// The client is still muted, drop the command without a reply.
+    Drop,
+    Disconnect,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        TokenBucket { rate, tokens: rate.burst as f64, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.rate).min(self.rate.burst as f64);
+        self.last = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// How long until the next token.
+    fn wait(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate.rate).clamp(0.0, 3600.0))
+    }
+}
+
+/// Token buckets for a single client.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    commands: TokenBucket,
+    messages: TokenBucket,
+    typing: TokenBucket,
+    updates: TokenBucket,
+    strikes: u32,
+    last_strike: Instant,
+    muted_until: Option<Instant>,
+    stats: Arc<RateLimitStats>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, stats: Arc<RateLimitStats>) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            commands: TokenBucket::new(limits.commands, now),
+            messages: TokenBucket::new(limits.messages, now),
+            typing: TokenBucket::new(limits.typing, now),
+            updates: TokenBucket::new(limits.updates, now),
+            limits,
+            strikes: 0,
+            last_strike: now,
+            muted_until: None,
+            stats,
+        }
+    }
+
+    /// Change the limits, keeping how far the client has already gone.
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.commands.rate = limits.commands;
+        self.messages.rate = limits.messages;
+        self.typing.rate = limits.typing;
+        self.updates.rate = limits.updates;
+        self.limits = limits;
+    }
+
+    pub fn check(&mut self, command: &Commands) -> Verdict {
+        self.check_at(command, Instant::now())
+    }
+
+    /// Decide what to do with a command that arrived at `now`.
+    pub fn check_at(&mut self, command: &Commands, now: Instant) -> Verdict {
+        match command {
+            // acknowledgements and goodbyes are never held back.
+            Commands::Success(_) | Commands::Disconnect(_) => return Verdict::Allow,
+            _ => {},
+        }
+
+        match self.muted_until {
+            Some(until) if now < until => return Verdict::Drop,
+            Some(_) => self.muted_until = None,
+            None => {},
+        }
+
+        if self.strikes > 0 && now.saturating_duration_since(self.last_strike) >= self.limits.forgive_after {
+            self.strikes = 0;
+        }
+
+        let mut kind = match command {
+            Commands::Message(_) | Commands::Edit(_) | Commands::Delete(_) | Commands::React(_) | Commands::Unreact(_) => Some(&mut self.messages),
+            Commands::Typing(_) => Some(&mut self.typing),
+            Commands::ClientUpdate(None) | Commands::ClientInfo(_) | Commands::Search(_) => Some(&mut self.updates),
+            _ => None,
+        };
+
+        if let Some(bucket) = kind.as_mut() {
+            if !bucket.has_token(now) {
+                let wait = bucket.wait();
+                return self.strike(now, wait);
+            }
+        }
+        if !self.commands.has_token(now) {
+            let wait = self.commands.wait();
+            return self.strike(now, wait);
+        }
+
+        if let Some(bucket) = kind {
+            bucket.take();
+        }
+        self.commands.take();
+        Verdict::Allow
+    }
+
+    fn strike(&mut self, now: Instant, wait: Duration) -> Verdict {
+        self.strikes += 1;
+        self.last_strike = now;
+        self.stats.throttled.fetch_add(1, Ordering::Relaxed);
+
+        if self.strikes >= self.limits.disconnect_after {
+            info!("rate limit: disconnecting client after {} strikes", self.strikes);
+            self.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+            Verdict::Disconnect
+        } else if self.strikes == self.limits.mute_after {
+            info!("rate limit: muting client for {:?}", self.limits.mute_duration);
+            self.stats.muted.fetch_add(1, Ordering::Relaxed);
+            self.muted_until = Some(now + self.limits.mute_duration);
+            Verdict::Mute(self.limits.mute_duration)
+        } else {
+            Verdict::Throttle(wait)
+        }
+    }
+}
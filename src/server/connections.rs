@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_MAX_CONNECTIONS_PER_ADDRESS: usize = 16;
+pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 64;
+/// Kept well below the size of the thread pool, so one address can't
+/// tie up every thread that handshakes run on.
+pub const DEFAULT_MAX_PENDING_HANDSHAKES_PER_ADDRESS: usize = 4;
+/// How long a new connection has to say what it wants.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limits on new connections, checked as soon as they are accepted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionLimits {
+    /// Connected clients and handshakes in progress, together.
+    pub max_connections: usize,
+    pub max_per_address: usize,
+    pub max_pending: usize,
+    pub max_pending_per_address: usize,
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_per_address: DEFAULT_MAX_CONNECTIONS_PER_ADDRESS,
+            max_pending: DEFAULT_MAX_PENDING_HANDSHAKES,
+            max_pending_per_address: DEFAULT_MAX_PENDING_HANDSHAKES_PER_ADDRESS,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// Why a new connection from `ip` should be refused, if it should.
+    /// `connected` and `connected_from` count the clients already
+    /// connected, in total and from `ip`.
+    pub fn refusal(&self, connected: usize, connected_from: usize, pending: &PendingHandshakes, ip: IpAddr) -> Option<&'static str> {
+        let (pending_total, pending_from) = (pending.total(), pending.for_address(ip));
+        if pending_total >= self.max_pending {
+            Some("server busy, try again later")
+        } else if connected + pending_total >= self.max_connections {
+            Some("server full")
+        } else if pending_from >= self.max_pending_per_address || connected_from + pending_from >= self.max_per_address {
+            Some("too many connections from your address")
+        } else {
+            None
+        }
+    }
+}
+
+/// Handshakes waiting on the thread pool, by the address they came from.
+///
+/// Cloning gives another handle to the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct PendingHandshakes {
+    addresses: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PendingHandshakes {
+    pub fn start(&self, ip: IpAddr) {
+        *self.addresses.lock().unwrap().entry(ip).or_insert(0) += 1;
+    }
+
+    pub fn finish(&self, ip: IpAddr) {
+        let mut addresses = self.addresses.lock().unwrap();
+        if let Some(count) = addresses.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                addresses.remove(&ip);
+            }
+        }
+    }
+
+    /// Handshakes in progress.
+    pub fn total(&self) -> usize {
+        self.addresses.lock().unwrap().values().sum()
+    }
+
+    /// Handshakes in progress from `ip`.
+    pub fn for_address(&self, ip: IpAddr) -> usize {
+        self.addresses.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crossbeam_channel::{Sender, Receiver, unbounded};
+
+use crate::server::client::client_profile::Client;
+
+/// What an observer needs to know about a connected client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSummary {
+    pub uuid: String,
+    pub name: String,
+    pub address: String,
+    pub last_heartbeat: Instant,
+}
+
+impl ClientSummary {
+    pub fn of(client: &Client) -> Self {
+        ClientSummary {
+            uuid: client.get_uuid(),
+            name: client.get_username(),
+            address: client.get_address(),
+            last_heartbeat: client.get_last_heartbeat(),
+        }
+    }
+}
+
+/// Something that happened on the server, sent from the server thread to
+/// anyone watching it, such as the control panel.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// The server is listening on the address.
+    Started(String),
+    Stopped,
+    /// The clients currently connected, sent about once a second.
+    Clients(Vec<ClientSummary>),
+    /// A line for the activity log.
+    Activity(String),
+}
+
+/// Hands server events to every subscriber. Subscribers that have gone
+/// away are dropped the next time an event is sent.
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<ServerEvent>>>>,
+}
+
+impl Events {
+    pub fn subscribe(&self) -> Receiver<ServerEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn is_watched(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    pub fn send(&self, event: ServerEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Send a line for the activity log.
+    pub fn activity(&self, line: String) {
+        self.send(ServerEvent::Activity(line));
+    }
+}
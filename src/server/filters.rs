@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
+
+use regex::{Regex, RegexBuilder};
+
+/// What happens to a message that a filter catches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterAction {
+    /// Refuse the message and tell the sender why.
+    Reject,
+    /// Relay the message with the offending part hidden.
+    Mask,
+    /// Relay the message unchanged and show it to the moderators.
+    Flag,
+}
+
+impl FromStr for FilterAction {
+    type Err = ();
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "reject" => Ok(FilterAction::Reject),
+            "mask" => Ok(FilterAction::Mask),
+            "flag" => Ok(FilterAction::Flag),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Why a filter caught a message, and the message with the offending part masked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caught {
+    pub reason: String,
+    pub masked: String,
+}
+
+/// A check run on every message before it is relayed. Custom filters
+/// implement this and are added with `FilterChain::add`.
+pub trait ContentFilter: fmt::Debug + Send + Sync {
+    /// Look at the content of a message, returning `None` if it is fine.
+    fn check(&self, content: &str) -> Option<Caught>;
+}
+
+/// Catches messages matching any of a list of regular expressions,
+/// ignoring case. Masking replaces each match with asterisks.
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    regex: Regex,
+}
+
+impl Blocklist {
+    pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+        // an empty pattern would match every message.
+        let pattern = if patterns.is_empty() {
+            String::from(r"[^\s\S]")
+        } else {
+            patterns.iter().map(|pattern| format!("(?:{})", pattern)).collect::<Vec<_>>().join("|")
+        };
+        Ok(Blocklist { regex: RegexBuilder::new(&pattern).case_insensitive(true).build()? })
+    }
+}
+
+impl ContentFilter for Blocklist {
+    fn check(&self, content: &str) -> Option<Caught> {
+        if !self.regex.is_match(content) {
+            return None;
+        }
+        let masked = self.regex.replace_all(content, |captures: &regex::Captures| "*".repeat(captures[0].chars().count()));
+        Some(Caught { reason: String::from("message contains a blocked word"), masked: masked.into_owned() })
+    }
+}
+
+/// Catches messages longer than a number of characters. Masking cuts them short.
+#[derive(Debug, Clone)]
+pub struct MaxLength(pub usize);
+
+impl ContentFilter for MaxLength {
+    fn check(&self, content: &str) -> Option<Caught> {
+        if content.chars().count() <= self.0 {
+            return None;
+        }
+        Some(Caught {
+            reason: format!("message is longer than {} characters", self.0),
+            masked: content.chars().take(self.0).collect(),
+        })
+    }
+}
+
+/// Catches messages with links in them. Masking strips the links out.
+#[derive(Debug, Clone)]
+pub struct Links {
+    regex: Regex,
+}
+
+impl Default for Links {
+    fn default() -> Self {
+        Links { regex: Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.\-]*://|www\.)\S+").unwrap() }
+    }
+}
+
+impl ContentFilter for Links {
+    fn check(&self, content: &str) -> Option<Caught> {
+        if !self.regex.is_match(content) {
+            return None;
+        }
+        Some(Caught { reason: String::from("message contains a link"), masked: self.regex.replace_all(content, "[link removed]").into_owned() })
+    }
+}
+
+/// The result of passing a message through a chain of filters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filtered {
+    pub content: String,
+    /// Why the message should be shown to the moderators, if it should.
+    pub flags: Vec<String>,
+}
+
+/// Filters run one after another, each seeing the message as the
+/// filters before it left it.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<(Arc<dyn ContentFilter>, FilterAction)>,
+}
+
+impl FilterChain {
+    pub fn add<F: ContentFilter + 'static>(&mut self, filter: F, action: FilterAction) -> &mut Self {
+        self.filters.push((Arc::new(filter), action));
+        self
+    }
+
+    /// Run a message through the chain. Returns the reason it was
+    /// rejected if a filter that rejects caught it.
+    pub fn apply(&self, content: &str) -> Result<Filtered, String> {
+        let mut filtered = Filtered { content: content.to_string(), flags: Vec::new() };
+        for (filter, action) in self.filters.iter() {
+            if let Some(caught) = filter.check(&filtered.content) {
+                match action {
+                    FilterAction::Reject => return Err(caught.reason),
+                    FilterAction::Mask => filtered.content = caught.masked,
+                    FilterAction::Flag => filtered.flags.push(caught.reason),
+                }
+            }
+        }
+        Ok(filtered)
+    }
+}
+
+/// The filters for a server. Rooms with a chain of their own use it
+/// instead of the server's, direct messages always use the server's.
+#[derive(Debug, Clone, Default)]
+pub struct ContentFilters {
+    server: FilterChain,
+    rooms: HashMap<String, FilterChain>,
+}
+
+impl ContentFilters {
+    /// The chain used for a room, or for the whole server when `room` is `None`.
+    /// A room without a chain of its own is given an empty one.
+    pub fn chain_mut(&mut self, room: Option<&str>) -> &mut FilterChain {
+        match room {
+            Some(room) => self.rooms.entry(room.to_string()).or_default(),
+            None => &mut self.server,
+        }
+    }
+
+    pub fn apply(&self, room: Option<&str>, content: &str) -> Result<Filtered, String> {
+        room.and_then(|room| self.rooms.get(room)).unwrap_or(&self.server).apply(content)
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    fs::OpenOptions,
+    io::prelude::*,
+    io::{BufReader, Error},
+    path::PathBuf,
+};
+
+use log::info;
+
+use crate::{
+    server::search::{SearchIndex, SearchQuery},
+    commands::Commands,
+};
+
+pub const DEFAULT_HISTORY_PATH: &str = "data/history.log";
+pub const MAX_REACTION_LENGTH: usize = 16;
+pub const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+
+/// A relayed chat message as it is currently known to the server.
+#[derive(Debug, Clone)]
+pub struct MessageRecord {
+    pub id: String,
+    pub from: String,
+    pub to: Option<String>,
+    pub room: Option<String>,
+    pub content: String,
+    pub time: u64,
+    pub edited: Option<u64>,
+    pub deleted: bool,
+    pub reply_to: Option<String>,
+    // id of the first message in the thread this message belongs to.
+    pub thread: Option<String>,
+    // who reacted with each reaction.
+    pub reactions: HashMap<String, HashSet<String>>,
+    // everyone the message was relayed to, used to pass on later changes.
+    pub recipients: Vec<String>,
+}
+
+impl MessageRecord {
+    /// Whether the given client sent or received this message.
+    pub fn is_visible_to(&self, uuid: &str) -> bool {
+        self.from == uuid || self.recipients.iter().any(|recipient| recipient == uuid)
+    }
+
+    /// The parameters of the `Commands::Message` that delivers this record.
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let mut params: HashMap<String, String> = [
+            (String::from("id"), self.id.clone()),
+            (String::from("from"), self.from.clone()),
+            (String::from("content"), self.content.clone()),
+            (String::from("time"), self.time.to_string()),
+        ].iter().cloned().collect();
+
+        if let Some(to) = &self.to {
+            params.insert(String::from("to"), to.clone());
+        }
+        if let Some(room) = &self.room {
+            params.insert(String::from("room"), room.clone());
+        }
+        if let Some(edited) = self.edited {
+            params.insert(String::from("edited"), edited.to_string());
+        }
+        if self.deleted {
+            params.insert(String::from("deleted"), String::from("true"));
+        }
+        if let Some(reply_to) = &self.reply_to {
+            params.insert(String::from("replyTo"), reply_to.clone());
+        }
+        if let Some(thread) = &self.thread {
+            params.insert(String::from("thread"), thread.clone());
+        }
+        if !self.reactions.is_empty() {
+            let counts: Vec<String> = self.reactions.iter().map(|(reaction, by)| format!("{}={}", reaction, by.len())).collect();
+            params.insert(String::from("reactions"), counts.join(","));
+        }
+        params
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Option<Self> {
+        Some(MessageRecord {
+            id: params.get("id")?.clone(),
+            from: params.get("from")?.clone(),
+            to: params.get("to").cloned(),
+            room: params.get("room").cloned(),
+            content: params.get("content").cloned().unwrap_or_default(),
+            time: params.get("time").and_then(|time| time.parse().ok()).unwrap_or(0),
+            edited: params.get("edited").and_then(|time| time.parse().ok()),
+            deleted: params.get("deleted").map(String::as_str) == Some("true"),
+            reply_to: params.get("replyTo").cloned(),
+            thread: params.get("thread").cloned(),
+            reactions: HashMap::new(),
+            recipients: params.get("recipients")
+                .map(|recipients| recipients.split(',').filter(|uuid| !uuid.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Persistent message history.
+///
+/// Every message and every change to one is appended to a log file as a
+/// command, one per line. The log is replayed when the history is opened
+/// to rebuild the current state of each message.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    messages: HashMap<String, MessageRecord>,
+    // replies in each thread, keyed by the id of the first message.
+    threads: HashMap<String, Vec<String>>,
+    index: SearchIndex,
+}
+
+impl History {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let mut history = History {
+            path: PathBuf::from(path),
+            messages: HashMap::new(),
+            threads: HashMap::new(),
+            index: SearchIndex::new(),
+        };
+
+        if history.path.exists() {
+            for line in BufReader::new(fs::File::open(&history.path)?).lines() {
+                history.apply(&Commands::from(line?));
+            }
+        }
+
+        info!("history: loaded {} messages", history.messages.len());
+        Ok(history)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&MessageRecord> {
+        self.messages.get(id)
+    }
+
+    /// Every message in the thread the given message belongs to, oldest first.
+    pub fn thread(&self, id: &str) -> Vec<&MessageRecord> {
+        let root = match self.messages.get(id) {
+            Some(record) => record.thread.clone().unwrap_or_else(|| record.id.clone()),
+            None => return Vec::new(),
+        };
+
+        let mut records: Vec<&MessageRecord> = self.messages.get(&root).into_iter().collect();
+        if let Some(replies) = self.threads.get(&root) {
+            records.extend(replies.iter().filter_map(|id| self.messages.get(id)));
+        }
+        records
+    }
+
+    /// Run a search on behalf of a client, only messages it sent or received
+    /// are included. Returns the total number of matches and the requested
+    /// page of them, newest first.
+    pub fn search(&self, query: &SearchQuery, uuid: &str) -> (usize, Vec<&MessageRecord>) {
+        let candidates: Vec<&MessageRecord> = if query.keywords.is_empty() {
+            self.messages.values().collect()
+        } else {
+            self.index.query(&query.keywords).iter().filter_map(|id| self.messages.get(id)).collect()
+        };
+
+        let mut matches: Vec<&MessageRecord> = candidates.into_iter()
+            .filter(|record| !record.deleted && record.is_visible_to(uuid))
+            .filter(|record| query.from.as_ref().map(|from| *from == record.from).unwrap_or(true))
+            .filter(|record| query.room.is_none() || query.room == record.room)
+            .filter(|record| query.after.map(|after| record.time >= after).unwrap_or(true))
+            .filter(|record| query.before.map(|before| record.time < before).unwrap_or(true))
+            .collect();
+        matches.sort_by_key(|record| std::cmp::Reverse((record.time, record.id.parse::<u64>().unwrap_or(0))));
+
+        let total = matches.len();
+        let page = matches.into_iter().skip(query.page.saturating_mul(query.size)).take(query.size).collect();
+        (total, page)
+    }
+
+    pub fn record(&mut self, record: MessageRecord) -> Result<(), Error> {
+        let mut params = record.to_params();
+        params.insert(String::from("recipients"), record.recipients.join(","));
+
+        let command = Commands::Message(Some(params));
+        self.append(&command)?;
+        self.apply(&command);
+        Ok(())
+    }
+
+    pub fn edit(&mut self, id: &str, content: &str, time: u64) -> Result<(), Error> {
+        let params: HashMap<String, String> = [
+            (String::from("id"), id.to_string()),
+            (String::from("content"), content.to_string()),
+            (String::from("time"), time.to_string()),
+        ].iter().cloned().collect();
+        let command = Commands::Edit(Some(params));
+
+        self.append(&command)?;
+        self.apply(&command);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, id: &str, time: u64) -> Result<(), Error> {
+        let params: HashMap<String, String> = [
+            (String::from("id"), id.to_string()),
+            (String::from("time"), time.to_string()),
+        ].iter().cloned().collect();
+        let command = Commands::Delete(Some(params));
+
+        self.append(&command)?;
+        self.apply(&command);
+        Ok(())
+    }
+
+    /// Add or remove a client's reaction on a message.
+    /// Returns the new count for the reaction, or None if nothing changed.
+    pub fn react(&mut self, id: &str, uuid: &str, reaction: &str, add: bool) -> Result<Option<usize>, Error> {
+        let changed = match self.messages.get(id) {
+            Some(record) => {
+                let reacted = record.reactions.get(reaction).map(|by| by.contains(uuid)).unwrap_or(false);
+                reacted != add
+            },
+            None => false,
+        };
+        if !changed {
+            return Ok(None);
+        }
+
+        let params: HashMap<String, String> = [
+            (String::from("id"), id.to_string()),
+            (String::from("by"), uuid.to_string()),
+            (String::from("reaction"), reaction.to_string()),
+        ].iter().cloned().collect();
+        let command = if add { Commands::React(Some(params)) } else { Commands::Unreact(Some(params)) };
+
+        self.append(&command)?;
+        self.apply(&command);
+        Ok(Some(self.messages[id].reactions.get(reaction).map(HashSet::len).unwrap_or(0)))
+    }
+
+    /// Reactions must be short and can't contain the characters used to list them.
+    pub fn validate_reaction(reaction: &str) -> Result<(), &'static str> {
+        if reaction.is_empty() || reaction.chars().count() > MAX_REACTION_LENGTH {
+            Err("reactions must be between 1 and 16 characters")
+        } else if reaction.contains(|c: char| c == ',' || c == '=' || c.is_whitespace()) {
+            Err("reactions can't contain ',', '=' or spaces")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn apply(&mut self, command: &Commands) {
+        match command {
+            Commands::Message(Some(params)) => {
+                if let Some(record) = MessageRecord::from_params(params) {
+                    if let Some(thread) = &record.thread {
+                        self.threads.entry(thread.clone()).or_default().push(record.id.clone());
+                    }
+                    self.index.add(&record.id, &record.content);
+                    self.messages.insert(record.id.clone(), record);
+                }
+            },
+            Commands::Edit(Some(params)) => {
+                let messages = &mut self.messages;
+                let record = params.get("id").and_then(|id| messages.get_mut(id));
+                if let (Some(record), Some(content)) = (record, params.get("content")) {
+                    self.index.remove(&record.id, &record.content);
+                    self.index.add(&record.id, content);
+                    record.content = content.clone();
+                    record.edited = params.get("time").and_then(|time| time.parse().ok());
+                }
+            },
+            Commands::Delete(Some(params)) => {
+                let messages = &mut self.messages;
+                if let Some(record) = params.get("id").and_then(|id| messages.get_mut(id)) {
+                    self.index.remove(&record.id, &record.content);
+                    record.content.clear();
+                    record.deleted = true;
+                }
+            },
+            Commands::React(Some(params)) => {
+                let record = params.get("id").and_then(|id| self.messages.get_mut(id));
+                if let (Some(record), Some(by), Some(reaction)) = (record, params.get("by"), params.get("reaction")) {
+                    record.reactions.entry(reaction.clone()).or_default().insert(by.clone());
+                }
+            },
+            Commands::Unreact(Some(params)) => {
+                let record = params.get("id").and_then(|id| self.messages.get_mut(id));
+                if let (Some(record), Some(by), Some(reaction)) = (record, params.get("by"), params.get("reaction")) {
+                    if let Some(reacted) = record.reactions.get_mut(reaction) {
+                        reacted.remove(by);
+                        if reacted.is_empty() {
+                            record.reactions.remove(reaction);
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn append(&self, command: &Commands) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        file.write_all(format!("{}\n", command.to_string()).as_bytes())
+    }
+}
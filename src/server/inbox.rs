@@ -0,0 +1,138 @@
+use std::{
+    fs,
+    fs::OpenOptions,
+    io::prelude::*,
+    io::{BufReader, Error},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+
+use crate::{commands::Commands, server::transfers::hex_encode};
+
+pub const DEFAULT_INBOX_PATH: &str = "data/inbox";
+pub const DEFAULT_INBOX_QUOTA: usize = 100;
+pub const DEFAULT_INBOX_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+#[derive(Debug)]
+pub enum InboxError {
+    QuotaExceeded,
+    UnknownRecipient,
+    Io(Error),
+}
+
+impl From<Error> for InboxError {
+    fn from(error: Error) -> Self {
+        InboxError::Io(error)
+    }
+}
+
+/// Persistent store for commands addressed to users that are not connected.
+///
+/// Every user gets a file named after their uuid in hex, holding one entry per
+/// line in the form `<unix seconds> <command>`. Entries older than the
+/// expiry are discarded and at most `quota` entries are kept per user.
+#[derive(Debug)]
+pub struct Inbox {
+    path: PathBuf,
+    quota: usize,
+    expiry: Duration,
+}
+
+impl Inbox {
+    pub fn new(path: &str, quota: usize, expiry: Duration) -> Self {
+        Inbox {
+            path: PathBuf::from(path),
+            quota,
+            expiry,
+        }
+    }
+
+    /// A copy of this inbox using the same storage with different limits.
+    pub fn with_limits(&self, quota: usize, expiry: Duration) -> Self {
+        Inbox {
+            path: self.path.clone(),
+            quota,
+            expiry,
+        }
+    }
+
+    /// Queue a command for the given user.
+    pub fn store(&self, uuid: &str, command: &Commands) -> Result<(), InboxError> {
+        let mut entries = self.read_entries(uuid)?;
+        if entries.len() >= self.quota {
+            return Err(InboxError::QuotaExceeded);
+        }
+
+        entries.push((Inbox::now(), command.to_string()));
+        self.write_entries(uuid, &entries)?;
+        Ok(())
+    }
+
+    /// Every unexpired command queued for the given user, oldest first.
+    /// Commands stay queued until they are removed once delivered.
+    pub fn pending(&self, uuid: &str) -> Result<Vec<Commands>, Error> {
+        let entries = self.read_entries(uuid)?;
+        info!("inbox: {} entries pending for {}", entries.len(), uuid);
+        Ok(entries.into_iter().map(|(_time, command)| Commands::from(command)).collect())
+    }
+
+    /// Remove the oldest entry matching a command that has been delivered.
+    /// Returns whether there was one.
+    pub fn remove(&self, uuid: &str, command: &Commands) -> Result<bool, Error> {
+        let mut entries = self.read_entries(uuid)?;
+        let position = match entries.iter().position(|(_time, entry)| Commands::from(entry.clone()) == *command) {
+            Some(position) => position,
+            None => return Ok(false),
+        };
+
+        entries.remove(position);
+        if entries.is_empty() {
+            fs::remove_file(self.file_path(uuid))?;
+        } else {
+            self.write_entries(uuid, &entries)?;
+        }
+        Ok(true)
+    }
+
+    fn read_entries(&self, uuid: &str) -> Result<Vec<(u64, String)>, Error> {
+        let file = self.file_path(uuid);
+        if !file.exists() {
+            return Ok(Vec::new());
+        }
+
+        let oldest = Inbox::now().saturating_sub(self.expiry.as_secs());
+        let mut entries = Vec::new();
+        for line in BufReader::new(fs::File::open(file)?).lines() {
+            let line = line?;
+            let mut parts = line.splitn(2, ' ');
+            if let (Some(time), Some(command)) = (parts.next(), parts.next()) {
+                match time.parse::<u64>() {
+                    Ok(time) if time >= oldest => entries.push((time, command.to_string())),
+                    _ => {},
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_entries(&self, uuid: &str, entries: &[(u64, String)]) -> Result<(), Error> {
+        fs::create_dir_all(&self.path)?;
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(self.file_path(uuid))?;
+        for (time, command) in entries {
+            writeln!(file, "{} {}", time, command)?;
+        }
+        file.flush()
+    }
+
+    fn file_path(&self, uuid: &str) -> PathBuf {
+        // uuids come from clients, hex keeps them inside the inbox directory
+        // without two of them sharing a file.
+        self.path.join(format!("{}.inbox", hex_encode(uuid.as_bytes())))
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
@@ -0,0 +1,24 @@
+use regex::Regex;
+
+/// The most users a single message can notify.
+pub const MAX_MENTIONS_PER_MESSAGE: usize = 10;
+
+/// Find the usernames mentioned with `@username` in a message.
+///
+/// An `@` only starts a mention at the beginning of the message or after
+/// a character that can't be part of a name, so email addresses are ignored.
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    let regex = Regex::new(r"(?:^|[^\w.\-@])@([\w.\-]+)").unwrap();
+    let mut mentions: Vec<String> = Vec::new();
+
+    for capture in regex.captures_iter(content) {
+        let name = capture[1].trim_end_matches('.').to_string();
+        if !name.is_empty() && !mentions.contains(&name) {
+            mentions.push(name);
+        }
+        if mentions.len() >= MAX_MENTIONS_PER_MESSAGE {
+            break;
+        }
+    }
+    mentions
+}
@@ -1,2 +1,18 @@
+pub mod accounts;
+pub mod admin;
+pub mod bans;
+pub mod broadcast;
 pub mod client;
+pub mod connections;
+pub mod events;
+pub mod filters;
+pub mod history;
+pub mod inbox;
+pub mod mentions;
+pub mod roles;
+pub mod rooms;
+pub mod search;
 pub mod server_profile;
+pub mod settings;
+pub mod transfers;
+pub mod typing;
@@ -0,0 +1,57 @@
+use std::str::FromStr;
+
+/// What an account is allowed to do, each role can do everything the
+/// roles below it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    #[default]
+    User,
+    /// Can kick clients, mute them in rooms, delete other people's messages
+    /// and remove members from rooms.
+    Moderator,
+    /// Can also ban and unban clients, make announcements and hand out roles below admin.
+    /// Banning an address is left to the owner.
+    Admin,
+    /// Can do anything, including shutting the server down.
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// The role needed to carry out an admin action.
+    pub fn required_for(action: &str) -> Role {
+        match action {
+            "kick" | "removeMember" | "mute" | "unmute" => Role::Moderator,
+            "ban" | "unban" | "bans" | "announce" | "role" => Role::Admin,
+            _ => Role::Owner,
+        }
+    }
+
+    /// Whether someone with this role can act on an account with `other`,
+    /// nobody can act on their equals or superiors except the owner.
+    pub fn outranks(&self, other: Role) -> bool {
+        *self == Role::Owner || *self > other
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            "owner" => Ok(Role::Owner),
+            _ => Err(()),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Tracks which connected clients have joined which rooms.
+///
+/// Rooms are created when the first client joins them and
+/// removed again once the last member has left. Mutes are kept separately
+/// so leaving and joining again doesn't lift them.
+#[derive(Debug, Default)]
+pub struct Rooms {
+    rooms: HashMap<String, HashSet<String>>,
+    muted: HashMap<(String, String), Option<Instant>>,
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Rooms::default()
+    }
+
+    pub fn join(&mut self, room: &str, uuid: &str) {
+        self.rooms.entry(room.to_string()).or_default().insert(uuid.to_string());
+    }
+
+    /// Returns false if the client was not a member of the room.
+    pub fn leave(&mut self, room: &str, uuid: &str) -> bool {
+        let removed = match self.rooms.get_mut(room) {
+            Some(members) => members.remove(uuid),
+            None => false,
+        };
+        self.rooms.retain(|_room, members| !members.is_empty());
+        removed
+    }
+
+    /// Remove a client from every room, returning the rooms it was in.
+    pub fn leave_all(&mut self, uuid: &str) -> Vec<String> {
+        let mut left = Vec::new();
+        for (room, members) in self.rooms.iter_mut() {
+            if members.remove(uuid) {
+                left.push(room.clone());
+            }
+        }
+        self.rooms.retain(|_room, members| !members.is_empty());
+        left
+    }
+
+    pub fn is_member(&self, room: &str, uuid: &str) -> bool {
+        self.rooms.get(room).map(|members| members.contains(uuid)).unwrap_or(false)
+    }
+
+    pub fn members(&self, room: &str) -> Vec<String> {
+        self.rooms.get(room).map(|members| members.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Stop a client sending messages to a room, for `duration` or until unmuted.
+    pub fn mute(&mut self, room: &str, uuid: &str, duration: Option<Duration>) {
+        self.muted.insert((room.to_string(), uuid.to_string()), duration.map(|duration| Instant::now() + duration));
+    }
+
+    /// Returns false if the client was not muted.
+    pub fn unmute(&mut self, room: &str, uuid: &str) -> bool {
+        self.muted.remove(&(room.to_string(), uuid.to_string())).is_some()
+    }
+
+    pub fn is_muted(&mut self, room: &str, uuid: &str) -> bool {
+        let key = (room.to_string(), uuid.to_string());
+        match self.muted.get(&key) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                self.muted.remove(&key);
+                false
+            },
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
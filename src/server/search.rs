@@ -0,0 +1,100 @@
+use std::collections::{HashMap, HashSet};
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 50;
+
+/// Split text into the lowercase words it is indexed under.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Inverted index from words to the ids of the messages containing them.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    terms: HashMap<String, HashSet<String>>,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        SearchIndex::default()
+    }
+
+    pub fn add(&mut self, id: &str, content: &str) {
+        for term in tokenize(content) {
+            self.terms.entry(term).or_default().insert(id.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, id: &str, content: &str) {
+        for term in tokenize(content) {
+            if let Some(ids) = self.terms.get_mut(&term) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// The ids of messages containing every one of the given words.
+    pub fn query(&self, terms: &[String]) -> HashSet<String> {
+        let mut sets = terms.iter().map(|term| self.terms.get(term));
+        let mut ids = match sets.next() {
+            Some(Some(ids)) => ids.clone(),
+            _ => return HashSet::new(),
+        };
+
+        for set in sets {
+            match set {
+                Some(set) => ids.retain(|id| set.contains(id)),
+                None => return HashSet::new(),
+            }
+        }
+        ids
+    }
+}
+
+/// A search over the message history, built from the parameters of a
+/// `Commands::Search`. Pages are numbered from zero.
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub keywords: Vec<String>,
+    pub from: Option<String>,
+    pub room: Option<String>,
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+    pub page: usize,
+    pub size: usize,
+}
+
+impl SearchQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, &'static str> {
+        fn number<T: std::str::FromStr>(params: &HashMap<String, String>, key: &str) -> Result<Option<T>, &'static str> {
+            match params.get(key) {
+                Some(value) => value.parse().map(Some).map_err(|_| "search parameters must be numbers"),
+                None => Ok(None),
+            }
+        }
+
+        let query = SearchQuery {
+            keywords: params.get("q").map(|q| tokenize(q)).unwrap_or_default(),
+            from: params.get("from").cloned(),
+            room: params.get("room").cloned(),
+            after: number(params, "after")?,
+            before: number(params, "before")?,
+            page: number(params, "page")?.unwrap_or(0),
+            size: number(params, "size")?.unwrap_or(DEFAULT_PAGE_SIZE),
+        };
+
+        if query.size == 0 || query.size > MAX_PAGE_SIZE {
+            Err("page size must be between 1 and 50")
+        } else if query.keywords.is_empty() && query.from.is_none() && query.room.is_none() {
+            Err("search needs keywords, an author or a room")
+        } else {
+            Ok(query)
+        }
+    }
+}
@@ -3,18 +3,35 @@ extern crate rayon;
 
 use crate::{
     server::{
-        client::client_profile::Client,
-
+        accounts::{Accounts, SignIn, DEFAULT_ACCOUNTS_PATH},
+        admin::{admin_message, token_matches},
+        bans::{Ban, BanTarget, Bans, DEFAULT_BANS_PATH},
+        broadcast::{announce_client, broadcast, broadcast_except, broadcast_room, deliver, membership, send_to},
+        client::client_profile::{Client, Presence, DEFAULT_IDLE_TIMEOUT},
+        events::{ClientSummary, Events, ServerEvent},
+        filters::ContentFilters,
+        client::outbound::{OutboundQueue, OutboundStats, OverflowPolicy, DEFAULT_OUTBOUND_CAPACITY},
+        client::rate_limit::{RateLimiter, RateLimits, RateLimitStats},
+        connections::{ConnectionLimits, PendingHandshakes},
+        history::{History, MessageRecord, DEFAULT_HISTORY_PATH, MAX_REACTIONS_PER_MESSAGE},
+        inbox::{Inbox, InboxError, DEFAULT_INBOX_PATH, DEFAULT_INBOX_QUOTA, DEFAULT_INBOX_EXPIRY},
+        mentions::parse_mentions,
+        roles::Role,
+        rooms::Rooms,
+        search::SearchQuery,
+        settings::LiveSettings,
+        transfers::{Transfers, MAX_CHUNKS_PER_GET, DEFAULT_TRANSFER_PATH, DEFAULT_MAX_FILE_SIZE, DEFAULT_TRANSFER_QUOTA},
+        typing::{TypingIndicators, TypingTarget, TYPING_TIMEOUT},
     },
     commands::Commands
 };
 
 use std::{
     sync::{Arc, Mutex},
-    net::{TcpStream, TcpListener},
+    net::{SocketAddr, TcpStream, TcpListener},
     collections::HashMap,
     io::prelude::*,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     io::Error,
     thread,
     io
@@ -22,7 +39,7 @@ use std::{
 
 use log::info;
 
-use crossbeam_channel::{Sender, Receiver, unbounded};
+use crossbeam_channel::{Sender, Receiver, bounded, unbounded};
 use rust_chat_server::ThreadPool;
 //use zeroize::Zeroize;
 //use parking_lot::FairMutex;
@@ -31,22 +48,80 @@ use rust_chat_server::ThreadPool;
 
 #[derive(Debug)]
 pub enum ServerMessages {
-    RequestUpdate(Arc<Mutex<TcpStream>>),
-    RequestInfo(String, Arc<Mutex<TcpStream>>),
+    RequestUpdate(String),
+    RequestInfo(String, String),
+    UpdateProfile(String, HashMap<String, String>),
+    Message(String, HashMap<String, String>),
+    Receipt(String, String, String),
+    /// A command from the inbox that the client has acknowledged.
+    Delivered(String, Commands),
+    /// A command the client never acknowledged, to keep in its inbox.
+    Undelivered(String, Commands),
+    Edit(String, String, String),
+    Delete(String, String),
+    Thread(String, String),
+    Search(String, HashMap<String, String>),
+    FileOffer(String, HashMap<String, String>),
+    FileChunk(String, HashMap<String, String>),
+    FileGet(String, HashMap<String, String>),
+    React(String, String, String, bool),
+    Typing(String, HashMap<String, String>),
+    Presence(String, HashMap<String, String>),
+    Join(String, String),
+    Leave(String, String),
     Disconnect(String),
+    Kick(String, String),
+    // the ban, and whether the address the client connects from is banned with it.
+    Ban(Ban, bool),
+    Unban(BanTarget),
+    Mute(String, String, Option<Duration>),
+    Unmute(String, String),
+    Announce(String),
+    Admin(String, HashMap<String, String>),
+    SetRole(String, Role),
+    RemoveMember(String, String),
+    ListClients(Sender<Vec<ClientSummary>>),
+    ListBans(Sender<Vec<Ban>>),
+    Stats(Sender<HashMap<String, String>>),
+    Reload(LiveSettings),
+    /// A new connection and the first thing it sent, or `None` if it never said anything.
+    Handshake(TcpStream, SocketAddr, Option<Commands>),
     Shutdown,
 }
 
+/// How long to wait for the server thread to answer a question.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
 // MARK: - server struct
 #[derive(Debug)]
 pub struct Server {
     name: Arc<String>,
     address: Arc<String>,
     author: Arc<String>,
+    motd: Arc<String>,
+    admin_token: Arc<String>,
 
     connected_clients: Arc<Mutex<HashMap<String, Client>>>,
-
-    thread_pool: ThreadPool,
+    inbox: Arc<Inbox>,
+    history_path: Arc<String>,
+    accounts_path: Arc<String>,
+    bans_path: Arc<String>,
+    transfer_path: Arc<String>,
+    max_file_size: u64,
+    transfer_quota: u64,
+    rooms: Arc<Mutex<Rooms>>,
+    idle_timeout: Duration,
+    outbound_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    outbound_stats: Arc<OutboundStats>,
+    rate_limits: RateLimits,
+    rate_limit_stats: Arc<RateLimitStats>,
+    events: Events,
+
+    connection_limits: ConnectionLimits,
+    content_filters: ContentFilters,
+
+    thread_pool: Arc<ThreadPool>,
 
     sender: Sender<ServerMessages>,
     receiver: Receiver<ServerMessages>,
@@ -61,8 +136,27 @@ impl Server {
             name: Arc::new(name.to_string()),
             address: Arc::new(address.to_string()),
             author: Arc::new(author.to_string()),
+            motd: Arc::new(String::new()),
+            admin_token: Arc::new(String::new()),
             connected_clients: Arc::new(Mutex::new(HashMap::new())),
-            thread_pool: ThreadPool::new(16), 
+            inbox: Arc::new(Inbox::new(DEFAULT_INBOX_PATH, DEFAULT_INBOX_QUOTA, DEFAULT_INBOX_EXPIRY)),
+            history_path: Arc::new(DEFAULT_HISTORY_PATH.to_string()),
+            accounts_path: Arc::new(DEFAULT_ACCOUNTS_PATH.to_string()),
+            bans_path: Arc::new(DEFAULT_BANS_PATH.to_string()),
+            transfer_path: Arc::new(DEFAULT_TRANSFER_PATH.to_string()),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            transfer_quota: DEFAULT_TRANSFER_QUOTA,
+            rooms: Arc::new(Mutex::new(Rooms::new())),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            outbound_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            overflow_policy: OverflowPolicy::Coalesce,
+            outbound_stats: Arc::new(OutboundStats::default()),
+            rate_limits: RateLimits::default(),
+            rate_limit_stats: Arc::new(RateLimitStats::default()),
+            events: Events::default(),
+            connection_limits: ConnectionLimits::default(),
+            content_filters: ContentFilters::default(),
+            thread_pool: Arc::new(ThreadPool::new(16)),
 
             sender,
             receiver,
@@ -84,6 +178,124 @@ impl Server {
         self.author.to_string()
     }
 
+    /// Set the message of the day sent to clients when they connect.
+    /// Must be called before the server is started, use `apply_settings` after.
+    #[allow(dead_code)]
+    pub fn set_motd(&mut self, motd: &str) {
+        self.motd = Arc::new(motd.to_string());
+    }
+
+    /// Set the token needed for `!admin:` requests, an empty token turns
+    /// remote administration off.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_admin_token(&mut self, token: &str) {
+        self.admin_token = Arc::new(token.to_string());
+    }
+
+    /// Replace the store used for messages to disconnected users.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_inbox(&mut self, inbox: Inbox) {
+        self.inbox = Arc::new(inbox);
+    }
+
+    /// Set where the message history is kept.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_history_path(&mut self, path: &str) {
+        self.history_path = Arc::new(path.to_string());
+    }
+
+    /// Set where known accounts are kept.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_accounts_path(&mut self, path: &str) {
+        self.accounts_path = Arc::new(path.to_string());
+    }
+
+    /// Set where bans are kept.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_bans_path(&mut self, path: &str) {
+        self.bans_path = Arc::new(path.to_string());
+    }
+
+    /// Set where files being transferred are stored, the largest file that
+    /// can be sent and how much space all transfers together may use.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_transfer_limits(&mut self, path: &str, max_file_size: u64, quota: u64) {
+        self.transfer_path = Arc::new(path.to_string());
+        self.max_file_size = max_file_size;
+        self.transfer_quota = quota;
+    }
+
+    /// Set how many threads the server's pool uses.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_thread_pool_size(&mut self, size: usize) {
+        self.thread_pool = Arc::new(ThreadPool::new(size));
+    }
+
+    /// Set how many connections the server accepts, in total and from a
+    /// single address, and how long new connections have to shake hands.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.connection_limits = limits;
+    }
+
+    /// Set the filters every message is passed through before it is relayed.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_content_filters(&mut self, filters: ContentFilters) {
+        self.content_filters = filters;
+    }
+
+    /// Set how long clients can be idle before they are marked away.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Set how many commands can be queued for each client and what
+    /// happens when a client falls too far behind.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_outbound_queue(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.outbound_capacity = capacity;
+        self.overflow_policy = policy;
+    }
+
+    /// Set how fast each client can send commands, and when those that
+    /// keep going over are muted or disconnected.
+    /// Must be called before the server is started.
+    #[allow(dead_code)]
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limits = limits;
+    }
+
+    /// Counts of commands refused from clients sending too fast.
+    #[allow(dead_code)]
+    pub fn get_rate_limit_stats(&self) -> Arc<RateLimitStats> {
+        self.rate_limit_stats.clone()
+    }
+
+    /// Counts of commands dropped or coalesced for slow clients.
+    #[allow(dead_code)]
+    pub fn get_outbound_stats(&self) -> Arc<OutboundStats> {
+        self.outbound_stats.clone()
+    }
+
+    /// Watch what the server is doing. Events are sent from the server
+    /// thread, so the receiver should be drained on another thread.
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
     pub fn start(&self) -> Result<(), io::Error>{
         println!("server: starting server...");
 
@@ -93,17 +305,46 @@ impl Server {
         let address = self.address.clone();
         let author = self.author.clone(); 
         let connected_clients = self.connected_clients.clone();
+        let mut motd = self.motd.to_string();
+        let admin_token = self.admin_token.clone();
+        let mut inbox = self.inbox.clone();
+        let rooms = self.rooms.clone();
+        let mut idle_timeout = self.idle_timeout;
+        let mut outbound_capacity = self.outbound_capacity;
+        let mut overflow_policy = self.overflow_policy;
+        let outbound_stats = self.outbound_stats.clone();
+        let mut rate_limits = self.rate_limits.clone();
+        let rate_limit_stats = self.rate_limit_stats.clone();
+        let mut connection_limits = self.connection_limits;
+        let thread_pool = self.thread_pool.clone();
+        let content_filters = self.content_filters.clone();
+        let events = self.events.clone();
         let sender = self.sender.clone();
         let receiver = self.receiver.clone();
 
-        // set up listener and buffer
-        let mut buffer = [0; 1024];
+        let mut history = History::open(&self.history_path)?;
+        let mut accounts = Accounts::open(&self.accounts_path)?;
+        let mut bans = Bans::open(&self.bans_path)?;
+        let bans_path = self.bans_path.clone();
+        let mut transfers = Transfers::open(&self.transfer_path, self.max_file_size, self.transfer_quota)?;
+
+        // set up listener
         let listener = TcpListener::bind(self.get_address())?;
         listener.set_nonblocking(true)?;
 
+        events.send(ServerEvent::Started(self.get_address()));
+
         println!("server: spawning threads");
         let _ = thread::Builder::new().name("Server Thread".to_string()).spawn(move || {
-            
+            // message ids are seeded from the clock so they stay unique across restarts.
+            let mut next_message_id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+            let mut typing = TypingIndicators::new(TYPING_TIMEOUT);
+            let started = Instant::now();
+            let mut last_summary = Instant::now();
+            let pending = PendingHandshakes::default();
+            // connections turned away for going over a limit.
+            let mut refused: u64 = 0;
+
             'outer: loop {
                 std::thread::sleep(Duration::from_millis(100));
 
@@ -116,76 +357,715 @@ impl Server {
                             println!("server: shutting down...");
                             break 'outer;
                         },
-                        ServerMessages::RequestUpdate(stream_arc) => {
-                            for (_k, v) in connected_clients.lock().unwrap().iter() {
-                                let mut stream = stream_arc.lock().unwrap();
-                                let _ = Server::transmit_data(&mut stream, v.to_string().as_str());
-
-                                if Server::read_data(&mut stream, &mut buffer).unwrap_or(Commands::Error(None)) == Commands::Success(None) {
-                                    println!("Success Confirmed");
+                        ServerMessages::RequestUpdate(uuid) => {
+                            // the client list is queued like any other update, so it
+                            // is acknowledged without holding up the server.
+                            let clients = connected_clients.lock().unwrap();
+                            if let Some(requester) = clients.get(&uuid) {
+                                // invisible clients look the same as ones that aren't connected.
+                                for client in clients.values().filter(|client| client.get_uuid() == uuid || client.get_presence() != Presence::Invisible) {
+                                    let _ = requester.sender.send(Commands::Client(Some(client.get_params())));
+                                }
+                            }
+                        },
+                        ServerMessages::RequestInfo(from, uuid) => {
+                            // the reply goes through the requester's queue, its socket
+                            // belongs to its own pass.
+                            let clients = connected_clients.lock().unwrap();
+                            let params = clients.get(&uuid).map(|client| client.get_params());
+                            if let Some(requester) = clients.get(&from) {
+                                let _ = requester.sender.send(Commands::Success(params));
+                            }
+                        },
+                        ServerMessages::UpdateProfile(uuid, params) => {
+                            let mut clients = connected_clients.lock().unwrap();
+                            let name_taken = match params.get("name") {
+                                Some(name) => clients.iter().any(|(k, client)| *k != uuid && client.get_username() == *name)
+                                    || accounts.is_taken(name, &uuid),
+                                None => false,
+                            };
+
+                            let result = match clients.get_mut(&uuid) {
+                                Some(_) if name_taken => Err("name is already in use"),
+                                Some(client) => client.update_profile(&params),
+                                None => continue,
+                            };
+
+                            match result {
+                                Ok(()) => {
+                                    if let Err(e) = accounts.update(&uuid, &clients[&uuid].get_username()) {
+                                        println!("server: failed to save account {}: {}", uuid, e);
+                                    }
+                                    let _ = clients[&uuid].sender.send(Commands::Success(None));
+                                    announce_client(&clients, &uuid);
+                                },
+                                Err(reason) => {
+                                    let _ = clients[&uuid].sender.send(Server::error_reply(reason));
+                                },
+                            }
+                        },
+                        ServerMessages::Message(from, params) => {
+                            // replies join the thread of the message they answer.
+                            let thread = match params.get("replyTo").map(|parent| history.get(parent)) {
+                                Some(Some(parent)) if parent.is_visible_to(&from) => Some(parent.thread.clone().unwrap_or_else(|| parent.id.clone())),
+                                Some(_) => {
+                                    if let Some(client) = connected_clients.lock().unwrap().get(&from) {
+                                        let _ = client.sender.send(Server::error_reply("unknown message to reply to"));
+                                    }
+                                    continue;
+                                },
+                                None => None,
+                            };
+
+                            let filtered = match content_filters.apply(params.get("room").map(String::as_str), params.get("content").map(String::as_str).unwrap_or_default()) {
+                                Ok(filtered) => filtered,
+                                Err(reason) => {
+                                    if let Some(client) = connected_clients.lock().unwrap().get(&from) {
+                                        let _ = client.sender.send(Server::error_reply(&reason));
+                                    }
+                                    continue;
+                                },
+                            };
+
+                            next_message_id += 1;
+                            let mut record = MessageRecord {
+                                id: next_message_id.to_string(),
+                                from: from.clone(),
+                                to: params.get("to").cloned(),
+                                room: params.get("room").cloned(),
+                                content: filtered.content,
+                                time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                                edited: None,
+                                deleted: false,
+                                reply_to: params.get("replyTo").cloned(),
+                                thread,
+                                reactions: HashMap::new(),
+                                recipients: Vec::new(),
+                            };
+
+                            let command = Commands::Message(Some(record.to_params()));
+                            let clients = connected_clients.lock().unwrap();
+                            let success = Commands::Success(Some([(String::from("id"), record.id.clone())].iter().cloned().collect()));
+
+                            let reply = if let Some(room) = record.room.clone() {
+                                let mut rooms = rooms.lock().unwrap();
+                                if rooms.is_muted(&room, &from) {
+                                    Server::error_reply("muted in this room")
+                                } else if rooms.is_member(&room, &from) {
+                                    record.recipients = rooms.members(&room).into_iter().filter(|uuid| *uuid != from).collect();
+                                    // members that are offline catch up from the history, only those with a full queue need their inbox.
+                                    for uuid in send_to(&clients, &record.recipients, &from, &command).into_iter().filter(|uuid| clients.contains_key(uuid)) {
+                                        if let Err(e) = inbox.store(&uuid, &command) {
+                                            println!("server: failed to store message for {}: {:?}", uuid, e);
+                                        }
+                                    }
+                                    success
                                 } else {
-                                    println!("no success read");
-                                    let error = Commands::Error(None);
-                                    let _ = Server::transmit_data(&mut stream, error.to_string().as_str());
+                                    Server::error_reply("not a member of this room")
+                                }
+                            } else {
+                                let to = record.to.clone().unwrap_or_default();
+                                record.recipients = vec![to.clone()];
+
+                                match deliver(&clients, &accounts, &inbox, &to, command) {
+                                    Ok(()) => success,
+                                    Err(InboxError::QuotaExceeded) => Server::error_reply("recipient inbox is full"),
+                                    Err(InboxError::UnknownRecipient) => Server::error_reply("unknown recipient"),
+                                    Err(InboxError::Io(e)) => {
+                                        println!("server: failed to store message for {}: {}", to, e);
+                                        Commands::Error(None)
+                                    },
+                                }
+                            };
+
+                            if let Commands::Success(_) = reply {
+                                // direct messages already reach everyone who could be mentioned.
+                                if let Some(room) = &record.room {
+                                    let mut params: HashMap<String, String> = [(String::from("id"), record.id.clone()), (String::from("from"), from.clone()), (String::from("room"), room.clone()), (String::from("content"), record.content.clone())].iter().cloned().collect();
+                                    params.insert(String::from("time"), record.time.to_string());
+                                    let mention = Commands::Mention(Some(params));
+
+                                    for username in parse_mentions(&record.content) {
+                                        let target = clients.values().find(|client| client.get_username() == username).map(|client| client.get_uuid())
+                                            .or_else(|| accounts.find_by_username(&username).map(|account| account.uuid.clone()));
+
+                                        match target {
+                                            Some(uuid) if uuid == from => {},
+                                            Some(uuid) => if let Err(e) = deliver(&clients, &accounts, &inbox, &uuid, mention.clone()) {
+                                                println!("server: failed to store mention for {}: {:?}", uuid, e);
+                                            },
+                                            None => {},
+                                        }
+                                    }
+                                }
+
+                                Server::flag(&clients, &accounts, &inbox, &events, &record.id, &from, record.room.as_deref(), &record.content, &filtered.flags);
+                                if let Err(e) = history.record(record) {
+                                    println!("server: failed to record message: {}", e);
                                 }
                             }
+                            if let Some(client) = clients.get(&from) {
+                                let _ = client.sender.send(reply);
+                            }
+                        },
+                        ServerMessages::Edit(uuid, id, content) => {
+                            let room = history.get(&id).and_then(|record| record.room.clone());
+                            let (content, flags) = match content_filters.apply(room.as_deref(), &content) {
+                                Ok(filtered) => (filtered.content, filtered.flags),
+                                Err(reason) => {
+                                    if let Some(client) = connected_clients.lock().unwrap().get(&uuid) {
+                                        let _ = client.sender.send(Server::error_reply(&reason));
+                                    }
+                                    continue;
+                                },
+                            };
+                            let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                            let result = Server::check_author(&history, &uuid, &id, accounts.role(&uuid) >= Role::Moderator)
+                                .and_then(|_| history.edit(&id, &content, time).map_err(|_| "failed to update history"));
+
+                            let clients = connected_clients.lock().unwrap();
+                            if result.is_ok() {
+                                Server::flag(&clients, &accounts, &inbox, &events, &id, &uuid, room.as_deref(), &content, &flags);
+                            }
+                            let params: HashMap<String, String> = [(String::from("id"), id.clone()), (String::from("content"), content), (String::from("edited"), time.to_string())].iter().cloned().collect();
+                            Server::finish_change(&clients, &inbox, &history, &uuid, &id, result, Commands::Edit(Some(params)));
+                        },
+                        ServerMessages::Delete(uuid, id) => {
+                            let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                            let result = Server::check_author(&history, &uuid, &id, accounts.role(&uuid) >= Role::Moderator)
+                                .and_then(|_| history.delete(&id, time).map_err(|_| "failed to update history"));
+
+                            let params: HashMap<String, String> = [(String::from("id"), id.clone())].iter().cloned().collect();
+                            Server::finish_change(&connected_clients.lock().unwrap(), &inbox, &history, &uuid, &id, result, Commands::Delete(Some(params)));
+                        },
+                        ServerMessages::React(uuid, id, reaction, add) => {
+                            let result = match history.get(&id) {
+                                Some(record) if record.deleted || !record.is_visible_to(&uuid) => Err("unknown message id"),
+                                Some(record) if add && !record.reactions.contains_key(&reaction) && record.reactions.len() >= MAX_REACTIONS_PER_MESSAGE => Err("too many different reactions on this message"),
+                                Some(_) => History::validate_reaction(&reaction)
+                                    .and_then(|_| history.react(&id, &uuid, &reaction, add).map_err(|_| "failed to update history")),
+                                None => Err("unknown message id"),
+                            };
+
+                            let clients = connected_clients.lock().unwrap();
+                            if let Ok(Some(count)) = result {
+                                let params: HashMap<String, String> = [(String::from("id"), id.clone()), (String::from("by"), uuid.clone()), (String::from("reaction"), reaction), (String::from("count"), count.to_string())].iter().cloned().collect();
+                                let change = if add { Commands::React(Some(params)) } else { Commands::Unreact(Some(params)) };
+
+                                // room messages go to whoever is in the room now, direct messages to both ends.
+                                let record = history.get(&id).unwrap();
+                                let audience = match &record.room {
+                                    Some(room) => rooms.lock().unwrap().members(room),
+                                    None => record.recipients.iter().cloned().chain(std::iter::once(record.from.clone())).collect(),
+                                };
+                                send_to(&clients, &audience, &uuid, &change);
+                            }
+
+                            if let Some(client) = clients.get(&uuid) {
+                                let _ = client.sender.send(match result {
+                                    Ok(_) => Commands::Success(None),
+                                    Err(reason) => Server::error_reply(reason),
+                                });
+                            }
                         },
-                        ServerMessages::RequestInfo(uuid, stream_arc) => {
-                            let mut stream = stream_arc.lock().unwrap();
-                            
+                        ServerMessages::Thread(uuid, id) => {
+                            let records: Vec<&MessageRecord> = history.thread(&id).into_iter().filter(|record| record.is_visible_to(&uuid)).collect();
                             if let Some(client) = connected_clients.lock().unwrap().get(&uuid) {
-                                let params: HashMap<String, String> = [(String::from("uuid"), client.get_uuid()), (String::from("name"), client.get_username()), (String::from("host"), client.get_address())].iter().cloned().collect();
-                                let command = Commands::Success(Some(params));
-                                let _ = Server::transmit_data(&mut stream, command.to_string().as_str());
+                                if records.is_empty() {
+                                    let _ = client.sender.send(Server::error_reply("unknown message id"));
+                                    continue;
+                                }
+
+                                for record in records.iter() {
+                                    let _ = client.sender.send(Commands::History(Some(record.to_params())));
+                                }
+                                let params: HashMap<String, String> = [(String::from("id"), id.clone()), (String::from("count"), records.len().to_string())].iter().cloned().collect();
+                                let _ = client.sender.send(Commands::Success(Some(params)));
+                            }
+                        },
+                        ServerMessages::Search(uuid, params) => {
+                            let clients = connected_clients.lock().unwrap();
+                            let client = match clients.get(&uuid) {
+                                Some(client) => client,
+                                None => continue,
+                            };
+
+                            match SearchQuery::from_params(&params) {
+                                Ok(query) => {
+                                    let (total, records) = history.search(&query, &uuid);
+                                    for record in records.iter() {
+                                        let _ = client.sender.send(Commands::History(Some(record.to_params())));
+                                    }
+                                    let params: HashMap<String, String> = [(String::from("total"), total.to_string()), (String::from("page"), query.page.to_string()), (String::from("count"), records.len().to_string())].iter().cloned().collect();
+                                    let _ = client.sender.send(Commands::Success(Some(params)));
+                                },
+                                Err(reason) => {
+                                    let _ = client.sender.send(Server::error_reply(reason));
+                                },
+                            }
+                        },
+                        ServerMessages::FileOffer(from, params) => {
+                            let clients = connected_clients.lock().unwrap();
+                            let known = params.get("to").map(|to| clients.contains_key(to) || accounts.get(to).is_some()).unwrap_or(false);
+
+                            let reply = if !known {
+                                Server::error_reply("unknown recipient")
                             } else {
-                                let command = Commands::Success(None);
-                                let _ = Server::transmit_data(&mut stream, command.to_string().as_str());
+                                match transfers.offer(&from, &params) {
+                                    Ok(transfer) => Commands::Success(Some(transfer.to_params())),
+                                    Err(reason) => Server::error_reply(reason),
+                                }
+                            };
+                            if let Some(client) = clients.get(&from) {
+                                let _ = client.sender.send(reply);
                             }
                         },
-                        ServerMessages::Disconnect(uuid) => {
-                            let mut clients = connected_clients.lock().unwrap();
-                            clients.remove(&uuid.to_string());
-                            let params: HashMap<String, String> = [(String::from("uuid"), uuid)].iter().cloned().collect();
-                            let command = Commands::ClientRemove(Some(params));
-                            let _ = connected_clients.lock().unwrap().iter().map(move |(_k, v)| {v.get_sender().send(command.clone())});
+                        ServerMessages::FileChunk(from, params) => {
+                            let clients = connected_clients.lock().unwrap();
+                            let id = params.get("transfer").cloned().unwrap_or_default();
+                            let seq = params.get("seq").and_then(|seq| seq.parse().ok()).unwrap_or(u64::MAX);
+                            let data = params.get("data").cloned().unwrap_or_default();
+
+                            let reply = match transfers.upload(&from, &id, seq, &data) {
+                                Ok(transfer) => {
+                                    let mut params: HashMap<String, String> = [(String::from("transfer"), id.clone()), (String::from("seq"), seq.to_string()), (String::from("received"), transfer.received.to_string())].iter().cloned().collect();
+                                    // the recipient is only told about the file once all of it has arrived intact.
+                                    if transfer.is_complete() {
+                                        params.insert(String::from("complete"), String::from("true"));
+                                        let offer = Commands::FileOffer(Some(transfer.to_params()));
+                                        if let Err(e) = deliver(&clients, &accounts, &inbox, &transfer.to, offer) {
+                                            println!("server: failed to store file offer for {}: {:?}", transfer.to, e);
+                                        }
+                                    }
+                                    Commands::Success(Some(params))
+                                },
+                                Err(reason) => {
+                                    let mut params: HashMap<String, String> = [(String::from("reason"), reason.to_string()), (String::from("transfer"), id.clone())].iter().cloned().collect();
+                                    if let Some(transfer) = transfers.get(&id) {
+                                        params.insert(String::from("received"), transfer.received.to_string());
+                                    }
+                                    Commands::Error(Some(params))
+                                },
+                            };
+                            if let Some(client) = clients.get(&from) {
+                                let _ = client.sender.send(reply);
+                            }
                         },
-                    }
-                }
+                        ServerMessages::FileGet(uuid, params) => {
+                            let id = params.get("transfer").cloned().unwrap_or_default();
+                            let seq: u64 = params.get("seq").and_then(|seq| seq.parse().ok()).unwrap_or(0);
+
+                            // the file is kept until the recipient says it has all of it, `done:true`,
+                            // so a chunk that went missing can be asked for again.
+                            let replies = if params.get("done").map(String::as_str) == Some("true") {
+                                match transfers.finish(&uuid, &id) {
+                                    Ok(()) => vec![Commands::Success(None)],
+                                    Err(reason) => vec![Server::error_reply(reason)],
+                                }
+                            } else {
+                                let count = params.get("count").and_then(|count| count.parse::<u64>().ok()).unwrap_or(1).clamp(1, MAX_CHUNKS_PER_GET);
+                                let mut replies = Vec::new();
+                                for seq in seq..seq.saturating_add(count) {
+                                    match transfers.download(&uuid, &id, seq) {
+                                        Ok(data) => replies.push(Commands::FileChunk(Some([(String::from("transfer"), id.clone()), (String::from("seq"), seq.to_string()), (String::from("data"), data)].iter().cloned().collect()))),
+                                        // asking for more chunks than are left stops at the last one.
+                                        Err(reason) => {
+                                            if replies.is_empty() {
+                                                replies.push(Server::error_reply(reason));
+                                            }
+                                            break;
+                                        },
+                                    }
+                                }
+                                replies
+                            };
+                            if let Some(client) = connected_clients.lock().unwrap().get(&uuid) {
+                                for reply in replies {
+                                    let _ = client.sender.send(reply);
+                                }
+                            }
+                        },
+                        ServerMessages::Receipt(by, id, status) => {
+                            let clients = connected_clients.lock().unwrap();
+
+                            // only the recipient of a direct message can say it got it.
+                            let reply = match history.get(&id) {
+                                Some(record) if !record.deleted && record.room.is_none() && record.to.as_deref() == Some(by.as_str()) => {
+                                    let from = &record.from;
+                                    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                                    let params: HashMap<String, String> = [(String::from("id"), id.clone()), (String::from("status"), status.clone()), (String::from("by"), by.clone()), (String::from("time"), time.to_string())].iter().cloned().collect();
+                                    let receipt = Commands::Receipt(Some(params));
+
+                                    if let Err(e) = deliver(&clients, &accounts, &inbox, from, receipt) {
+                                        println!("server: failed to store receipt for {}: {:?}", from, e);
+                                    }
+                                    Commands::Success(None)
+                                },
+                                _ => Server::error_reply("unknown message id"),
+                            };
 
-                println!("server: checking for new connections");
-                if let Ok((mut stream, _addr)) = listener.accept() {
-                    stream.set_read_timeout(Some(Duration::from_millis(1000))).unwrap();
-                    let _ = stream.set_nonblocking(false);
+                            // delivery receipts are generated by the server, only read receipts come from the client.
+                            if status == "read" {
+                                if let Some(client) = clients.get(&by) {
+                                    let _ = client.sender.send(reply);
+                                }
+                            }
+                        },
+                        ServerMessages::Delivered(uuid, command) => {
+                            if let Err(e) = inbox.remove(&uuid, &command) {
+                                println!("server: failed to update inbox for {}: {}", uuid, e);
+                            }
+                        },
+                        ServerMessages::Undelivered(uuid, command) => {
+                            if let Err(e) = inbox.store(&uuid, &command) {
+                                println!("server: failed to store undelivered command for {}: {:?}", uuid, e);
+                            }
+                        },
+                        ServerMessages::Typing(from, params) => {
+                            let target = match (params.get("to"), params.get("room")) {
+                                (Some(to), _) => TypingTarget::Client(to.clone()),
+                                (None, Some(room)) => TypingTarget::Room(room.clone()),
+                                (None, None) => continue,
+                            };
+
+                            let clients = connected_clients.lock().unwrap();
+                            let rooms = rooms.lock().unwrap();
+                            if let TypingTarget::Room(room) = &target {
+                                if !rooms.is_member(room, &from) {
+                                    continue;
+                                }
+                            }
 
-                    let request = Commands::Request(None);
-                    let _ = Server::transmit_data(&mut stream, &request.to_string().as_str());
+                            // refreshes are not relayed, only changes in state.
+                            if params.get("state").map(String::as_str) == Some("stop") {
+                                if typing.stop(&from, target.clone()) {
+                                    Server::notify_typing(&clients, &rooms, &from, &target, "stop");
+                                }
+                            } else if typing.start(&from, target.clone()) {
+                                Server::notify_typing(&clients, &rooms, &from, &target, "start");
+                            }
+                        },
+                        ServerMessages::Presence(uuid, params) => {
+                            broadcast_except(&connected_clients.lock().unwrap(), &uuid, &Commands::Presence(Some(params)));
+                        },
+                        ServerMessages::Join(uuid, room) => {
+                            let mut rooms = rooms.lock().unwrap();
+                            let clients = connected_clients.lock().unwrap();
+                            let joined = !rooms.is_member(&room, &uuid);
+                            rooms.join(&room, &uuid);
+                            if joined {
+                                broadcast_room(&clients, &rooms, &room, &uuid, &membership(&room, &uuid, true));
+                            }
+                            if let Some(client) = clients.get(&uuid) {
+                                let _ = client.sender.send(Commands::Success(None));
+                            }
+                        },
+                        ServerMessages::Leave(uuid, room) => {
+                            let mut rooms = rooms.lock().unwrap();
+                            let clients = connected_clients.lock().unwrap();
+                            let reply = if rooms.leave(&room, &uuid) {
+                                Server::stop_typing_in(&mut typing, &clients, &rooms, &uuid, &room);
+                                broadcast_room(&clients, &rooms, &room, &uuid, &membership(&room, &uuid, false));
+                                Commands::Success(None)
+                            } else {
+                                Server::error_reply("not a member of this room")
+                            };
+                            if let Some(client) = clients.get(&uuid) {
+                                let _ = client.sender.send(reply);
+                            }
+                        },
+                        ServerMessages::Admin(from, params) => {
+                            // the same requests as remote administration, allowed by the sender's role.
+                            let role = accounts.role(&from);
+                            let action = params.get("action").cloned().unwrap_or_default();
+                            let target = match (params.get("uuid"), params.get("username")) {
+                                (Some(uuid), _) => accounts.role(uuid),
+                                (None, Some(username)) => accounts.find_by_username(username).map(|account| account.role).unwrap_or_default(),
+                                // an address can be shared with anyone, so only the owner can ban one.
+                                (None, None) if params.contains_key("address") => Role::Owner,
+                                (None, None) => Role::User,
+                            };
+                            let new_role = params.get("role").and_then(|role| role.parse::<Role>().ok()).unwrap_or_default();
+                            // banning the address with the client can shut out everyone behind it, so it is the owner's call.
+                            let link_address = params.get("linkAddress").map(String::as_str) == Some("true");
+
+                            let reply = if role < Role::required_for(&action) || !role.outranks(target) || (action == "role" && !role.outranks(new_role))
+                                || (link_address && role != Role::Owner) {
+                                Server::error_reply("not allowed")
+                            } else if action == "bans" {
+                                if let Some(client) = connected_clients.lock().unwrap().get(&from) {
+                                    for ban in bans.list() {
+                                        let _ = client.sender.send(Commands::Ban(Some(ban.to_params())));
+                                    }
+                                }
+                                Commands::Success(None)
+                            } else {
+                                match admin_message(&params) {
+                                    Ok(message) => {
+                                        let _ = sender.send(message);
+                                        Commands::Success(None)
+                                    },
+                                    Err(reason) => Server::error_reply(reason),
+                                }
+                            };
+                            if let Some(client) = connected_clients.lock().unwrap().get(&from) {
+                                let _ = client.sender.send(reply);
+                            }
+                        },
+                        ServerMessages::SetRole(uuid, role) => {
+                            match accounts.set_role(&uuid, role) {
+                                Ok(true) => {
+                                    let mut clients = connected_clients.lock().unwrap();
+                                    if let Some(client) = clients.get_mut(&uuid) {
+                                        client.set_role(role);
+                                        let _ = client.sender.send(Commands::Client(Some(client.get_params())));
+                                        announce_client(&clients, &uuid);
+                                    }
+                                    events.activity(format!("{} is now {}", uuid, role.as_str()));
+                                },
+                                Ok(false) => events.activity(format!("can't give {} a role, the account is unknown", uuid)),
+                                Err(e) => println!("server: failed to save account {}: {}", uuid, e),
+                            }
+                        },
+                        ServerMessages::RemoveMember(room, uuid) => {
+                            let mut rooms = rooms.lock().unwrap();
+                            if rooms.leave(&room, &uuid) {
+                                let clients = connected_clients.lock().unwrap();
+                                Server::stop_typing_in(&mut typing, &clients, &rooms, &uuid, &room);
+                                let leave = membership(&room, &uuid, false);
+                                broadcast_room(&clients, &rooms, &room, &uuid, &leave);
+                                if let Some(client) = clients.get(&uuid) {
+                                    let _ = client.sender.send(leave);
+                                }
+                                events.activity(format!("{} removed from {}", uuid, room));
+                            }
+                        },
+                        ServerMessages::ListClients(reply) => {
+                            let _ = reply.send(connected_clients.lock().unwrap().values().map(ClientSummary::of).collect());
+                        },
+                        ServerMessages::Stats(reply) => {
+                            let stats = [
+                                ("clients", connected_clients.lock().unwrap().len() as u64),
+                                ("banned", bans.list().len() as u64),
+                                ("uptime", started.elapsed().as_secs()),
+                                ("dropped", outbound_stats.dropped()),
+                                ("coalesced", outbound_stats.coalesced()),
+                                ("disconnected", outbound_stats.disconnected()),
+                                ("throttled", rate_limit_stats.throttled()),
+                                ("flood_mutes", rate_limit_stats.muted()),
+                                ("flood_disconnects", rate_limit_stats.disconnected()),
+                                ("pending", pending.total() as u64),
+                                ("refused", refused),
+                            ];
+                            let _ = reply.send(stats.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect());
+                        },
+                        ServerMessages::Reload(settings) => {
+                            motd = settings.motd;
+                            idle_timeout = settings.idle_timeout;
+                            rate_limits = settings.rate_limits;
+                            connection_limits = settings.connection_limits;
+                            for client in connected_clients.lock().unwrap().values_mut() {
+                                client.set_idle_timeout(idle_timeout);
+                                client.set_rate_limits(rate_limits.clone());
+                            }
+                            inbox = Arc::new(inbox.with_limits(settings.inbox_quota, settings.inbox_expiry));
+                            transfers.set_limits(settings.max_file_size, settings.transfer_quota);
+                            outbound_capacity = settings.queue_capacity;
+                            overflow_policy = settings.overflow_policy;
+                            // picks up bans added to or lifted from the file by hand.
+                            match Bans::open(&bans_path) {
+                                Ok(reopened) => {
+                                    bans = reopened;
+                                    Server::disconnect_banned(&mut connected_clients.lock().unwrap(), &rooms, &mut typing, &inbox, &bans, &events);
+                                },
+                                Err(e) => println!("server: failed to reload bans: {}", e),
+                            }
+                            info!("server: settings reloaded");
+                            events.activity(String::from("settings reloaded"));
+                        },
+                        ServerMessages::Disconnect(uuid) => {
+                            let mut clients = connected_clients.lock().unwrap();
+                            if let Some(client) = Server::remove_client(&mut clients, &rooms, &mut typing, &inbox, &uuid) {
+                                events.activity(format!("{} ({}) disconnected", client.get_username(), uuid));
+                            }
+                        },
+                        ServerMessages::Kick(uuid, reason) => {
+                            let mut clients = connected_clients.lock().unwrap();
+                            if let Some(mut client) = Server::remove_client(&mut clients, &rooms, &mut typing, &inbox, &uuid) {
+                                client.kick(&reason);
+                                events.activity(format!("{} ({}) kicked: {}", client.get_username(), uuid, reason));
+                            }
+                        },
+                        ServerMessages::Ban(ban, link_address) => {
+                            let mut new_bans = vec![ban.clone()];
+                            let mut clients = connected_clients.lock().unwrap();
+                            // when asked, a client banned by uuid is banned from the address it connects from
+                            // too, until the uuid ban is lifted. An address that is already banned keeps its own ban.
+                            if let (true, BanTarget::Uuid(uuid)) = (link_address, &ban.target) {
+                                if let Some(address) = clients.get(uuid).and_then(|client| client.get_peer_ip()).and_then(|ip| ban.linked_address(ip)) {
+                                    if !bans.list().iter().any(|existing| existing.target == address.target) {
+                                        new_bans.push(address);
+                                    }
+                                }
+                            }
+                            for ban in new_bans {
+                                events.activity(format!("banned {}: {}", ban.target, ban.reason));
+                                if let Err(e) = bans.add(ban) {
+                                    println!("server: failed to save bans: {}", e);
+                                }
+                            }
 
-                    match Server::read_data(&mut stream, &mut buffer) {
-                        Ok(command) => {
-                            println!("Server: new connection sent - {:?}", command);
+                            Server::disconnect_banned(&mut clients, &rooms, &mut typing, &inbox, &bans, &events);
+                        },
+                        ServerMessages::Unban(target) => {
+                            match bans.remove(&target) {
+                                Ok(true) => events.activity(format!("lifted ban of {}", target)),
+                                Ok(false) => {},
+                                Err(e) => println!("server: failed to save bans: {}", e),
+                            }
+                        },
+                        ServerMessages::ListBans(reply) => {
+                            let _ = reply.send(bans.list().to_vec());
+                        },
+                        ServerMessages::Mute(room, uuid, duration) => {
+                            rooms.lock().unwrap().mute(&room, &uuid, duration);
+                            events.activity(format!("{} muted in {}", uuid, room));
+                        },
+                        ServerMessages::Unmute(room, uuid) => {
+                            if rooms.lock().unwrap().unmute(&room, &uuid) {
+                                events.activity(format!("{} unmuted in {}", uuid, room));
+                            }
+                        },
+                        ServerMessages::Handshake(mut stream, peer, command) => {
+                            pending.finish(peer.ip());
+                            let command = match command {
+                                Some(command) => command,
+                                None => continue,
+                            };
+                            // secrets and tokens are kept out of the log.
+                            let mut logged = command.clone();
+                            if let Commands::Connect(Some(params)) | Commands::Admin(Some(params)) = &mut logged {
+                                for key in ["secret", "token"] {
+                                    if let Some(value) = params.get_mut(key) {
+                                        *value = String::from("<hidden>");
+                                    }
+                                }
+                            }
+                            println!("Server: new connection sent - {:?}", logged);
                             match command {
                                 Commands::Connect(Some(data)) => {
-                                    let uuid = data.get("uuid").unwrap();
-                                    let username = data.get("name").unwrap();
-                                    let address = data.get("host").unwrap();
-        
-                                    println!("{}", format!("Server: new Client connection: _addr = {}", address ));
-        
-                                    let client = Client::new(stream, sender.clone(), &uuid, &username, &address);
-
-                                    connected_clients.lock().unwrap().insert(uuid.to_string(), client);
-        
-                                    let params: HashMap<String, String> = [(String::from("name"), username.clone()), (String::from("host"), address.clone()), (String::from("uuid"), uuid.clone())].iter().cloned().collect();
-                                    let new_client = Commands::Client(Some(params));
-        
-                                    let _ = connected_clients.lock().unwrap().iter().map(|(_k, v)| v.sender.send(new_client.clone()));
+                                    if let Some(ban) = bans.find(data.get("uuid").map(String::as_str), data.get("name").map(String::as_str), peer.ip()) {
+                                        let _ = Server::transmit_data(&mut stream, ban.refusal().to_string().as_str());
+                                        events.activity(format!("refused {} from {}: banned", data.get("uuid").cloned().unwrap_or_default(), peer.ip()));
+                                        continue;
+                                    }
+                                    let (uuid, username, address) = match (data.get("uuid"), data.get("name"), data.get("host")) {
+                                        (Some(uuid), Some(username), Some(address)) => (uuid, username, address),
+                                        _ => {
+                                            let _ = Server::transmit_data(&mut stream, Server::error_reply("connect needs a uuid, name and host").to_string().as_str());
+                                            continue;
+                                        },
+                                    };
+    
+                                    println!("Server: new Client connection: _addr = {}", address);
+
+                                    // a name that couldn't be set with a profile update can't be connected with either.
+                                    if let Err(reason) = Client::validate_username(username) {
+                                        let _ = Server::transmit_data(&mut stream, Server::error_reply(reason).to_string().as_str());
+                                        events.activity(format!("refused {} from {}: {}", uuid, peer.ip(), reason));
+                                        continue;
+                                    }
+                                    // a second connection can't take over a client that is already here.
+                                    if connected_clients.lock().unwrap().contains_key(uuid) {
+                                        let _ = Server::transmit_data(&mut stream, Server::error_reply("already connected").to_string().as_str());
+                                        events.activity(format!("refused {} from {}: already connected", uuid, peer.ip()));
+                                        continue;
+                                    }
+                                    // usernames are unique, so mentions and bans by name pick out one account.
+                                    if accounts.is_taken(username, uuid) {
+                                        let _ = Server::transmit_data(&mut stream, Server::error_reply("name is already in use").to_string().as_str());
+                                        events.activity(format!("refused {} from {}: {} is taken", uuid, peer.ip(), username));
+                                        continue;
+                                    }
+                                    let signed_in = match accounts.sign_in(uuid, username, data.get("secret").map(String::as_str)) {
+                                        Ok(SignIn::Refused) => {
+                                            let _ = Server::transmit_data(&mut stream, Server::error_reply("not authorised").to_string().as_str());
+                                            events.activity(format!("refused {} from {}: wrong secret", uuid, peer.ip()));
+                                            continue;
+                                        },
+                                        Ok(signed_in) => signed_in,
+                                        Err(e) => {
+                                            println!("server: failed to save account {}: {}", uuid, e);
+                                            let _ = Server::transmit_data(&mut stream, Commands::Error(None).to_string().as_str());
+                                            continue;
+                                        },
+                                    };
+
+                                    let outbound = OutboundQueue::new(outbound_capacity, overflow_policy, outbound_stats.clone());
+                                    let limiter = RateLimiter::new(rate_limits.clone(), rate_limit_stats.clone());
+                                    let mut client = Client::new(stream, sender.clone(), outbound, limiter, uuid, username, address, idle_timeout);
+                                    client.set_role(accounts.role(uuid));
+
+                                    // the secret is only ever sent once, the client has to keep it to connect again.
+                                    let _ = client.sender.send(match signed_in {
+                                        SignIn::Issued(secret) => Commands::Success(Some([(String::from("secret"), secret)].iter().cloned().collect())),
+                                        _ => Commands::Success(None),
+                                    });
+
+                                    if !motd.is_empty() {
+                                        let params: HashMap<String, String> = [(String::from("name"), name.to_string()), (String::from("owner"), author.to_string()), (String::from("motd"), motd.clone())].iter().cloned().collect();
+                                        let _ = client.sender.send(Commands::Info(Some(params)));
+                                    }
+
+                                    match inbox.pending(uuid) {
+                                        Ok(queued) => for command in queued {
+                                            // commands that are never acknowledged are done with once queued.
+                                            if !client.queue_from_inbox(command.clone()) {
+                                                if let Err(e) = inbox.remove(uuid, &command) {
+                                                    println!("server: failed to update inbox for {}: {}", uuid, e);
+                                                }
+                                            }
+                                        },
+                                        Err(e) => println!("server: failed to read inbox for {}: {}", uuid, e),
+                                    }
+
+                                    let mut clients = connected_clients.lock().unwrap();
+                                    clients.insert(uuid.to_string(), client);
+                                    announce_client(&clients, uuid);
+                                    events.activity(format!("{} ({}) connected from {}", username, uuid, address));
                                 },    
+                                Commands::Admin(Some(params)) => {
+                                    let reply = if !token_matches(&admin_token, params.get("token").map(String::as_str)) {
+                                        events.activity(format!("refused admin request from {}", peer.ip()));
+                                        Server::error_reply("not authorised")
+                                    } else if params.get("action").map(String::as_str) == Some("clients") {
+                                        let list: String = connected_clients.lock().unwrap().values().map(|client| client.to_string()).collect();
+                                        let _ = Server::transmit_data(&mut stream, &list);
+                                        Commands::Success(None)
+                                    } else if params.get("action").map(String::as_str) == Some("bans") {
+                                        let list: String = bans.list().iter().map(|ban| Commands::Ban(Some(ban.to_params())).to_string()).collect();
+                                        let _ = Server::transmit_data(&mut stream, &list);
+                                        Commands::Success(None)
+                                    } else {
+                                        match admin_message(&params) {
+                                            Ok(message) => {
+                                                let _ = sender.send(message);
+                                                Commands::Success(None)
+                                            },
+                                            Err(reason) => Server::error_reply(reason),
+                                        }
+                                    };
+                                    let _ = Server::transmit_data(&mut stream, reply.to_string().as_str());
+                                },
                                 // TODO: - correct connection reset error when getting info.
                                 Commands::Info(None) => {
                                     println!("Server: info requested");
-                                    let params: HashMap<String, String> = [(String::from("name"), name.to_string().clone()), (String::from("owner"), author.to_string().clone())].iter().cloned().collect();
+                                    let mut params: HashMap<String, String> = [(String::from("name"), name.to_string().clone()), (String::from("owner"), author.to_string().clone())].iter().cloned().collect();
+                                    if !motd.is_empty() {
+                                        params.insert(String::from("motd"), motd.clone());
+                                    }
                                     let command = Commands::Info(Some(params));
-        
+    
                                     let _ = Server::transmit_data(&mut stream, command.to_string().as_str());
                                 },
                                 _ => {
@@ -194,8 +1074,60 @@ impl Server {
                                 },
                             }
                         },
-                        Err(_) => println!("ERROR: stream closed"),
+                        ServerMessages::Announce(content) => {
+                            let params: HashMap<String, String> = [(String::from("content"), content.clone()), (String::from("time"), SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).to_string())].iter().cloned().collect();
+                            broadcast(&connected_clients.lock().unwrap(), &Commands::Announce(Some(params)));
+                            events.activity(format!("announced: {}", content));
+                        },
+                    }
+                }
+
+                transfers.expire();
+
+                let expired = typing.expire();
+                if !expired.is_empty() {
+                    let clients = connected_clients.lock().unwrap();
+                    let rooms = rooms.lock().unwrap();
+                    for (from, target) in expired.iter() {
+                        Server::notify_typing(&clients, &rooms, from, target, "stop");
+                    }
+                }
+
+                if let Err(e) = bans.expire() {
+                    println!("server: failed to save bans: {}", e);
+                }
+
+                println!("server: checking for new connections");
+                // only the limits are checked here, the handshake runs on the thread
+                // pool so a slow connector can't hold up everyone else.
+                while let Ok((mut stream, peer)) = listener.accept() {
+                    let ip = peer.ip();
+                    // banned addresses are turned away before the handshake.
+                    if let Some(ban) = bans.find(None, None, ip) {
+                        let _ = Server::transmit_data(&mut stream, ban.refusal().to_string().as_str());
+                        events.activity(format!("refused connection from {}: banned", ip));
+                        continue;
+                    }
+
+                    let refusal = {
+                        let clients = connected_clients.lock().unwrap();
+                        let connected_from = clients.values().filter(|client| client.get_peer_ip() == Some(ip)).count();
+                        connection_limits.refusal(clients.len(), connected_from, &pending, ip)
+                    };
+                    if let Some(reason) = refusal {
+                        refused += 1;
+                        let _ = Server::transmit_data(&mut stream, Server::error_reply(reason).to_string().as_str());
+                        events.activity(format!("refused connection from {}: {}", ip, reason));
+                        continue;
                     }
+
+                    pending.start(ip);
+                    let deadline = Instant::now() + connection_limits.handshake_timeout;
+                    let sender = sender.clone();
+                    thread_pool.execute(move || {
+                        let command = Server::handshake(&mut stream, deadline);
+                        let _ = sender.send(ServerMessages::Handshake(stream, peer, command));
+                    });
                 }
                 // TODO: end -
 
@@ -204,18 +1136,213 @@ impl Server {
                 for (_k, client) in connected_clients.lock().unwrap().iter_mut() {
                     client.handle_connection();
                 }
+
+                if last_summary.elapsed() >= Duration::from_secs(1) && events.is_watched() {
+                    last_summary = Instant::now();
+                    let summaries = connected_clients.lock().unwrap().values().map(ClientSummary::of).collect();
+                    events.send(ServerEvent::Clients(summaries));
+                }
             }
             println!("server: stopped");
+            events.send(ServerEvent::Stopped);
         });
         println!("server: started");
         Ok(())
     }
 
+    /// Change the settings that don't need a restart, the server loop
+    /// picks them up on its next pass.
+    pub fn apply_settings(&self, settings: LiveSettings) {
+        let _ = self.sender.send(ServerMessages::Reload(settings));
+    }
+
+    /// Hand a message to the server thread.
+    pub fn send(&self, message: ServerMessages) {
+        let _ = self.sender.send(message);
+    }
+
+    /// Disconnect a client, telling it why.
+    #[allow(dead_code)]
+    pub fn kick(&self, uuid: &str, reason: &str) {
+        let _ = self.sender.send(ServerMessages::Kick(uuid.to_string(), reason.to_string()));
+    }
+
+    /// Stop a client from connecting again, and with `link_address` the
+    /// address it is connected from as well. The client is disconnected if
+    /// it is online.
+    #[allow(dead_code)]
+    pub fn ban(&self, uuid: &str, reason: &str, link_address: bool) {
+        let _ = self.sender.send(ServerMessages::Ban(Ban::new(BanTarget::Uuid(uuid.to_string()), reason, None), link_address));
+    }
+
+    /// The bans in force, or `None` if the server isn't running.
+    #[allow(dead_code)]
+    pub fn list_bans(&self) -> Option<Vec<Ban>> {
+        let (reply, answer) = bounded(1);
+        let _ = self.sender.send(ServerMessages::ListBans(reply));
+        answer.recv_timeout(REPLY_TIMEOUT).ok()
+    }
+
+    /// Give an account a role, the account must have connected before.
+    #[allow(dead_code)]
+    pub fn set_role(&self, uuid: &str, role: Role) {
+        let _ = self.sender.send(ServerMessages::SetRole(uuid.to_string(), role));
+    }
+
+    /// Send an announcement to every connected client.
+    #[allow(dead_code)]
+    pub fn announce(&self, content: &str) {
+        let _ = self.sender.send(ServerMessages::Announce(content.to_string()));
+    }
+
+    /// The clients connected right now, or `None` if the server isn't running.
+    #[allow(dead_code)]
+    pub fn list_clients(&self) -> Option<Vec<ClientSummary>> {
+        let (reply, answer) = bounded(1);
+        let _ = self.sender.send(ServerMessages::ListClients(reply));
+        answer.recv_timeout(REPLY_TIMEOUT).ok()
+    }
+
+    /// Counters describing the running server, or `None` if it isn't running.
+    #[allow(dead_code)]
+    pub fn get_stats(&self) -> Option<HashMap<String, String>> {
+        let (reply, answer) = bounded(1);
+        let _ = self.sender.send(ServerMessages::Stats(reply));
+        answer.recv_timeout(REPLY_TIMEOUT).ok()
+    }
+
     pub fn stop(&self) {
         info!("server: sending stop message");
         let _ = self.sender.send(ServerMessages::Shutdown);
     }
 
+    /// Take a client off the server and tell everyone it has gone.
+    fn remove_client(clients: &mut HashMap<String, Client>, rooms: &Mutex<Rooms>, typing: &mut TypingIndicators, inbox: &Inbox, uuid: &str) -> Option<Client> {
+        let mut client = clients.remove(uuid)?;
+        let stopped = typing.remove_client(uuid);
+        for command in client.undelivered() {
+            if let Err(e) = inbox.store(uuid, &command) {
+                println!("server: failed to store undelivered command for {}: {:?}", uuid, e);
+            }
+        }
+
+        let mut rooms = rooms.lock().unwrap();
+        for (from, target) in stopped.iter() {
+            Server::notify_typing(clients, &rooms, from, target, "stop");
+        }
+        for room in rooms.leave_all(uuid) {
+            broadcast_room(clients, &rooms, &room, uuid, &membership(&room, uuid, false));
+        }
+        let params: HashMap<String, String> = [(String::from("uuid"), uuid.to_string())].iter().cloned().collect();
+        broadcast(clients, &Commands::ClientRemove(Some(params)));
+        Some(client)
+    }
+
+    /// Disconnect the clients that are covered by a ban.
+    fn disconnect_banned(clients: &mut HashMap<String, Client>, rooms: &Mutex<Rooms>, typing: &mut TypingIndicators, inbox: &Inbox, bans: &Bans, events: &Events) {
+        let banned: Vec<(String, String)> = clients.values()
+            .filter_map(|client| {
+                let ban = bans.find(Some(&client.get_uuid()), Some(&client.get_username()), client.get_peer_ip()?)?;
+                Some((client.get_uuid(), ban.reason.clone()))
+            })
+            .collect();
+        for (uuid, reason) in banned {
+            if let Some(mut client) = Server::remove_client(clients, rooms, typing, inbox, &uuid) {
+                client.kick(&format!("banned: {}", reason));
+                events.activity(format!("{} ({}) disconnected, banned", client.get_username(), uuid));
+            }
+        }
+    }
+
+    /// Show a message that a filter flagged to every moderator who is online.
+    #[allow(clippy::too_many_arguments)]
+    fn flag(clients: &HashMap<String, Client>, accounts: &Accounts, inbox: &Inbox, events: &Events, id: &str, from: &str, room: Option<&str>, content: &str, reasons: &[String]) {
+        if reasons.is_empty() {
+            return;
+        }
+
+        let reason = reasons.join(", ");
+        let mut params: HashMap<String, String> = [
+            (String::from("id"), id.to_string()),
+            (String::from("from"), from.to_string()),
+            (String::from("content"), content.to_string()),
+            (String::from("reason"), reason.clone()),
+        ].iter().cloned().collect();
+        if let Some(room) = room {
+            params.insert(String::from("room"), room.to_string());
+        }
+
+        let flag = Commands::Flag(Some(params));
+        for client in clients.values().filter(|client| client.get_role() >= Role::Moderator) {
+            if let Err(e) = deliver(clients, accounts, inbox, &client.get_uuid(), flag.clone()) {
+                println!("server: failed to store flag for {}: {:?}", client.get_uuid(), e);
+            }
+        }
+        events.activity(format!("flagged message {} from {}: {}", id, from, reason));
+    }
+
+    fn error_reply(reason: &str) -> Commands {
+        Commands::Error(Some([(String::from("reason"), reason.to_string())].iter().cloned().collect()))
+    }
+
+    /// Check that a message can be changed by `uuid`, moderators may change
+    /// other people's messages when `moderator` is set.
+    fn check_author(history: &History, uuid: &str, id: &str, moderator: bool) -> Result<(), &'static str> {
+        match history.get(id) {
+            Some(record) if record.deleted => Err("message has been deleted"),
+            Some(record) if record.from != uuid && !moderator => Err("only the author can change a message"),
+            Some(_) => Ok(()),
+            None => Err("unknown message id"),
+        }
+    }
+
+    /// Answer the client that changed a message and pass the change on to
+    /// everyone who received the original and its author, queueing it for
+    /// anyone offline.
+    fn finish_change(clients: &HashMap<String, Client>, inbox: &Inbox, history: &History, uuid: &str, id: &str, result: Result<(), &'static str>, change: Commands) {
+        let reply = match result {
+            Ok(()) => {
+                // the author only needs telling when a moderator made the change.
+                let recipients: Vec<String> = history.get(id).map(|record| record.recipients.iter().cloned().chain(std::iter::once(record.from.clone())).collect()).unwrap_or_default();
+                for recipient in send_to(clients, &recipients, uuid, &change) {
+                    if let Err(e) = inbox.store(&recipient, &change) {
+                        println!("server: failed to store change for {}: {:?}", recipient, e);
+                    }
+                }
+                Commands::Success(None)
+            },
+            Err(reason) => Server::error_reply(reason),
+        };
+
+        if let Some(client) = clients.get(uuid) {
+            let _ = client.sender.send(reply);
+        }
+    }
+
+    /// Stop a client's typing indicator in a room it has left.
+    fn stop_typing_in(typing: &mut TypingIndicators, clients: &HashMap<String, Client>, rooms: &Rooms, uuid: &str, room: &str) {
+        let target = TypingTarget::Room(room.to_string());
+        if typing.stop(uuid, target.clone()) {
+            Server::notify_typing(clients, rooms, uuid, &target, "stop");
+        }
+    }
+
+    /// Fan a typing indicator out to everyone who can see the target.
+    /// These are sent once and never retried.
+    fn notify_typing(clients: &HashMap<String, Client>, rooms: &Rooms, from: &str, target: &TypingTarget, state: &str) {
+        let mut params: HashMap<String, String> = [(String::from("from"), from.to_string()), (String::from("state"), state.to_string())].iter().cloned().collect();
+        match target {
+            TypingTarget::Client(uuid) => {
+                params.insert(String::from("to"), uuid.clone());
+                send_to(clients, std::slice::from_ref(uuid), from, &Commands::Typing(Some(params)));
+            },
+            TypingTarget::Room(room) => {
+                params.insert(String::from("room"), room.clone());
+                broadcast_room(clients, rooms, room, from, &Commands::Typing(Some(params)));
+            },
+        }
+    }
+
     fn transmit_data(stream: &mut TcpStream, data: &str) -> Result<(), Error>{
         println!("Transmitting...");
         println!("data: {}", data);
@@ -230,11 +1357,30 @@ impl Server {
         Ok(())
     }
 
-    fn read_data(stream: &mut TcpStream, buffer: &mut [u8; 1024]) -> Result<Commands, Error> {
-        let _ = stream.read(buffer)?;
-        let command = Commands::from(buffer);
+    /// Ask a new connection what it wants, giving up at `deadline`. Runs on
+    /// the thread pool, connectors that run out of time are told so.
+    fn handshake(stream: &mut TcpStream, deadline: Instant) -> Option<Commands> {
+        let timed_out = |stream: &mut TcpStream| {
+            let _ = Server::transmit_data(stream, Server::error_reply("handshake timed out").to_string().as_str());
+            None
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return timed_out(stream);
+        }
+        stream.set_nonblocking(false).ok()?;
+        stream.set_read_timeout(Some(remaining)).ok()?;
+        stream.set_write_timeout(Some(remaining)).ok()?;
 
-        Ok(command)
+        Server::transmit_data(stream, Commands::Request(None).to_string().as_str()).ok()?;
+        let mut buffer = [0; 1024];
+        match stream.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(_) => Some(Commands::from(&mut buffer)),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => timed_out(stream),
+            Err(_) => None,
+        }
     }
 }
 
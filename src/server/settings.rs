@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use crate::server::{
+    client::{outbound::OverflowPolicy, rate_limit::RateLimits},
+    connections::ConnectionLimits,
+};
+
+/// Settings that can be changed while the server is running, passed to
+/// the server loop with `ServerMessages::Reload`.
+///
+/// The idle timeout and rate limits apply to every client straight away,
+/// queue settings only to clients that connect afterwards. Connection
+/// limits don't affect clients that are already connected. The bans file
+/// is read again too, and clients it now bans are disconnected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveSettings {
+    pub motd: String,
+    pub idle_timeout: Duration,
+    pub inbox_quota: usize,
+    pub inbox_expiry: Duration,
+    pub max_file_size: u64,
+    pub transfer_quota: u64,
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub rate_limits: RateLimits,
+    pub connection_limits: ConnectionLimits,
+}
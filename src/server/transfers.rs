@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    fs,
+    fs::OpenOptions,
+    io::prelude::*,
+    io::{BufReader, Error, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use openssl::sha::sha256;
+use log::info;
+
+use crate::commands::Commands;
+
+pub const DEFAULT_TRANSFER_PATH: &str = "data/transfers";
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_TRANSFER_QUOTA: u64 = 100 * 1024 * 1024;
+/// Bytes per chunk, small enough that a hex encoded chunk fits in one read.
+pub const CHUNK_SIZE: usize = 384;
+/// How many chunks one `!fileGet:` can ask for, so a download isn't held to
+/// one chunk per pass of the server.
+pub const MAX_CHUNKS_PER_GET: u64 = 64;
+/// How long an unfinished or undownloaded transfer is kept.
+pub const TRANSFER_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    pub received: u64,
+    // seconds since the epoch, so the expiry carries over a restart.
+    created: u64,
+}
+
+impl Transfer {
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    /// The parameters of the `Commands::FileOffer` sent to the recipient.
+    pub fn to_params(&self) -> HashMap<String, String> {
+        [
+            (String::from("transfer"), self.id.clone()),
+            (String::from("from"), self.from.clone()),
+            (String::from("name"), self.name.clone()),
+            (String::from("size"), self.size.to_string()),
+            (String::from("hash"), self.hash.clone()),
+            (String::from("chunk"), CHUNK_SIZE.to_string()),
+        ].iter().cloned().collect()
+    }
+
+    /// The parameters kept on disk next to the upload.
+    fn to_record(&self) -> HashMap<String, String> {
+        let mut params = self.to_params();
+        params.insert(String::from("to"), self.to.clone());
+        params.insert(String::from("created"), self.created.to_string());
+        params
+    }
+
+    fn from_record(params: &HashMap<String, String>) -> Option<Self> {
+        Some(Transfer {
+            id: params.get("transfer")?.clone(),
+            from: params.get("from")?.clone(),
+            to: params.get("to")?.clone(),
+            name: params.get("name")?.clone(),
+            size: params.get("size")?.parse().ok()?,
+            hash: params.get("hash")?.clone(),
+            received: 0,
+            created: params.get("created")?.parse().ok()?,
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created) >= TRANSFER_EXPIRY.as_secs()
+    }
+}
+
+/// Files being passed between clients through the server.
+///
+/// Uploads are written to a temporary file per transfer, one chunk at a
+/// time in order, and checked against the offered hash once complete.
+/// Each upload has its offer saved beside it so it outlives a restart.
+/// Space for the whole file is reserved against the quota when it is offered,
+/// and given back once the recipient has downloaded the last chunk.
+#[derive(Debug)]
+pub struct Transfers {
+    path: PathBuf,
+    max_file_size: u64,
+    quota: u64,
+    transfers: HashMap<String, Transfer>,
+    next_id: u64,
+}
+
+impl Transfers {
+    /// Pick up the transfers left in `path` by an earlier run. Uploads
+    /// carry on from the last whole chunk, files without an offer are removed.
+    pub fn open(path: &str, max_file_size: u64, quota: u64) -> Result<Self, Error> {
+        let mut transfers = Transfers {
+            path: PathBuf::from(path),
+            max_file_size,
+            quota,
+            transfers: HashMap::new(),
+            next_id: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0),
+        };
+        fs::create_dir_all(&transfers.path)?;
+
+        for entry in fs::read_dir(&transfers.path)? {
+            let file = entry?.path();
+            if extension(&file) == Some("offer") {
+                if let Some(transfer) = transfers.restore(&file)? {
+                    transfers.transfers.insert(transfer.id.clone(), transfer);
+                }
+            }
+        }
+        for entry in fs::read_dir(&transfers.path)? {
+            let file = entry?.path();
+            let id = file.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            if extension(&file) == Some("part") && !transfers.transfers.contains_key(id) {
+                fs::remove_file(&file)?;
+            }
+        }
+
+        info!("transfers: resumed {} transfers", transfers.transfers.len());
+        Ok(transfers)
+    }
+
+    /// Read back a saved offer, dropping it if its upload is gone or it has expired.
+    fn restore(&self, offer: &Path) -> Result<Option<Transfer>, Error> {
+        let line = BufReader::new(fs::File::open(offer)?).lines().next().transpose()?.unwrap_or_default();
+        let mut transfer = match Commands::from(line) {
+            Commands::FileOffer(Some(params)) => Transfer::from_record(&params),
+            _ => None,
+        };
+
+        let upload = offer.with_extension("part");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match (&mut transfer, fs::metadata(&upload)) {
+            (Some(transfer), Ok(metadata)) if !transfer.is_expired(now) && metadata.len() <= transfer.size => {
+                // a chunk cut short by the restart is dropped, the sender resends it.
+                transfer.received = match metadata.len() {
+                    len if len == transfer.size => len,
+                    len => len - len % CHUNK_SIZE as u64,
+                };
+                OpenOptions::new().write(true).open(&upload)?.set_len(transfer.received)?;
+            },
+            _ => {
+                let _ = fs::remove_file(&upload);
+                fs::remove_file(offer)?;
+                return Ok(None);
+            },
+        }
+        Ok(transfer)
+    }
+
+    /// Change the limits for transfers offered from now on.
+    pub fn set_limits(&mut self, max_file_size: u64, quota: u64) {
+        self.max_file_size = max_file_size;
+        self.quota = quota;
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Transfer> {
+        self.transfers.get(id)
+    }
+
+    pub fn offer(&mut self, from: &str, params: &HashMap<String, String>) -> Result<&Transfer, &'static str> {
+        let to = params.get("to").ok_or("missing recipient")?;
+        let name = params.get("name").ok_or("missing file name")?;
+        let hash = params.get("hash").ok_or("missing hash")?.to_lowercase();
+        let size: u64 = params.get("size").and_then(|size| size.parse().ok()).ok_or("missing or invalid size")?;
+
+        if name.is_empty() || name.len() > 255 {
+            return Err("file name must be between 1 and 255 bytes");
+        }
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("hash must be a hex encoded sha256");
+        }
+        if size == 0 || size > self.max_file_size {
+            return Err("file is too large");
+        }
+        let reserved: u64 = self.transfers.values().map(|transfer| transfer.size).sum();
+        if reserved + size > self.quota {
+            return Err("server is out of space for transfers");
+        }
+
+        self.next_id += 1;
+        let transfer = Transfer {
+            id: self.next_id.to_string(),
+            from: from.to_string(),
+            to: to.clone(),
+            name: name.clone(),
+            size,
+            hash,
+            received: 0,
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        };
+        OpenOptions::new().write(true).create(true).truncate(true).open(self.file_path(&transfer.id)).map_err(|_| "failed to create transfer")?;
+        fs::write(self.offer_path(&transfer.id), Commands::FileOffer(Some(transfer.to_record())).to_string() + "\n").map_err(|_| "failed to create transfer")?;
+
+        info!("transfers: {} offered {} ({} bytes) to {}", from, transfer.name, size, to);
+        let id = transfer.id.clone();
+        self.transfers.insert(id.clone(), transfer);
+        Ok(&self.transfers[&id])
+    }
+
+    /// Store the next chunk of an upload. Chunks must arrive in order,
+    /// once the last one is stored the file is checked against its hash.
+    pub fn upload(&mut self, uuid: &str, id: &str, seq: u64, data: &str) -> Result<&Transfer, &'static str> {
+        let path = self.file_path(id);
+        let transfer = match self.transfers.get_mut(id) {
+            Some(transfer) if transfer.from == uuid => transfer,
+            _ => return Err("unknown transfer"),
+        };
+
+        if transfer.is_complete() {
+            return Err("transfer is already complete");
+        }
+        if seq != transfer.received / CHUNK_SIZE as u64 {
+            return Err("chunk out of order");
+        }
+
+        let bytes = hex_decode(data).ok_or("chunk is not valid hex")?;
+        let remaining = transfer.size - transfer.received;
+        let expected = remaining.min(CHUNK_SIZE as u64) as usize;
+        if bytes.len() != expected {
+            return Err("chunk has the wrong size");
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&path).map_err(|_| "failed to store chunk")?;
+        file.write_all(&bytes).map_err(|_| "failed to store chunk")?;
+        transfer.received += bytes.len() as u64;
+
+        if transfer.is_complete() {
+            let contents = fs::read(&path).map_err(|_| "failed to verify transfer")?;
+            if hex_encode(&sha256(&contents)) != transfer.hash {
+                self.remove(id);
+                return Err("file does not match its hash");
+            }
+        }
+        Ok(&self.transfers[id])
+    }
+
+    /// Read a chunk of a completed transfer for its recipient, hex encoded.
+    /// Chunks aren't acknowledged, so any of them can be read again until
+    /// the recipient says it has the whole file or the transfer expires.
+    pub fn download(&self, uuid: &str, id: &str, seq: u64) -> Result<String, &'static str> {
+        let transfer = match self.transfers.get(id) {
+            Some(transfer) if transfer.to == uuid && transfer.is_complete() => transfer,
+            _ => return Err("unknown transfer"),
+        };
+
+        let offset = seq.saturating_mul(CHUNK_SIZE as u64);
+        if offset >= transfer.size {
+            return Err("chunk out of range");
+        }
+
+        let mut file = fs::File::open(self.file_path(id)).map_err(|_| "failed to read transfer")?;
+        let mut buffer = vec![0; (transfer.size - offset).min(CHUNK_SIZE as u64) as usize];
+        file.seek(SeekFrom::Start(offset)).and_then(|_| file.read_exact(&mut buffer)).map_err(|_| "failed to read transfer")?;
+        Ok(hex_encode(&buffer))
+    }
+
+    /// Remove a completed transfer once its recipient has all of it.
+    pub fn finish(&mut self, uuid: &str, id: &str) -> Result<(), &'static str> {
+        match self.transfers.get(id) {
+            Some(transfer) if transfer.to == uuid && transfer.is_complete() => info!("transfers: {} downloaded {}", uuid, transfer.name),
+            _ => return Err("unknown transfer"),
+        }
+        self.remove(id);
+        Ok(())
+    }
+
+    /// Remove transfers that have been kept for too long.
+    pub fn expire(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let expired: Vec<String> = self.transfers.values()
+            .filter(|transfer| transfer.is_expired(now))
+            .map(|transfer| transfer.id.clone())
+            .collect();
+
+        for id in expired {
+            self.remove(&id);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        let _ = fs::remove_file(self.file_path(id));
+        let _ = fs::remove_file(self.offer_path(id));
+        self.transfers.remove(id);
+    }
+
+    fn file_path(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}.part", id))
+    }
+
+    fn offer_path(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}.offer", id))
+    }
+}
+
+fn extension(file: &Path) -> Option<&str> {
+    file.extension().and_then(|extension| extension.to_str())
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// `is_multiple_of` needs a newer rustc than the one we build with.
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+pub fn hex_decode(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 || !data.is_ascii() {
+        return None;
+    }
+    (0..data.len()).step_by(2).map(|i| u8::from_str_radix(&data[i..i + 2], 16).ok()).collect()
+}
@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How long a typing indicator lasts if the client does not refresh it.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a client is typing, either a direct message or a room.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TypingTarget {
+    Client(String),
+    Room(String),
+}
+
+/// Active typing indicators, these are never persisted.
+#[derive(Debug)]
+pub struct TypingIndicators {
+    active: HashMap<(String, TypingTarget), Instant>,
+    timeout: Duration,
+}
+
+impl TypingIndicators {
+    pub fn new(timeout: Duration) -> Self {
+        TypingIndicators {
+            active: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Start or refresh an indicator, returns true if it was not already active.
+    pub fn start(&mut self, uuid: &str, target: TypingTarget) -> bool {
+        self.active.insert((uuid.to_string(), target), Instant::now()).is_none()
+    }
+
+    /// Returns true if the indicator was active.
+    pub fn stop(&mut self, uuid: &str, target: TypingTarget) -> bool {
+        self.active.remove(&(uuid.to_string(), target)).is_some()
+    }
+
+    /// Remove and return every indicator that has not been refreshed in time.
+    pub fn expire(&mut self) -> Vec<(String, TypingTarget)> {
+        let timeout = self.timeout;
+        let expired: Vec<(String, TypingTarget)> = self.active.iter()
+            .filter(|(_key, started)| started.elapsed() >= timeout)
+            .map(|(key, _started)| key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            self.active.remove(key);
+        }
+        expired
+    }
+
+    /// Remove and return every indicator belonging to a client.
+    pub fn remove_client(&mut self, uuid: &str) -> Vec<(String, TypingTarget)> {
+        let removed: Vec<(String, TypingTarget)> = self.active.keys()
+            .filter(|(from, _target)| from == uuid)
+            .cloned()
+            .collect();
+
+        for key in removed.iter() {
+            self.active.remove(key);
+        }
+        removed
+    }
+}